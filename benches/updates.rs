@@ -0,0 +1,45 @@
+use std::hint::black_box;
+
+use anon::bot::entities::UpdateMessage;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+
+/// Updates recorded from telegram. Non-ascii text comes escaped in some of them, which makes
+/// the parser allocate instead of borrowing.
+const FIXTURES: &[(&str, &str)] = &[
+    ("private_text", include_str!("fixtures/private_text.json")),
+    (
+        "private_text_escaped",
+        include_str!("fixtures/private_text_escaped.json"),
+    ),
+    (
+        "group_send_command",
+        include_str!("fixtures/group_send_command.json"),
+    ),
+    (
+        "photo_with_caption",
+        include_str!("fixtures/photo_with_caption.json"),
+    ),
+    (
+        "callback_query",
+        include_str!("fixtures/callback_query.json"),
+    ),
+];
+
+fn parse_updates(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_update");
+
+    for (name, fixture) in FIXTURES {
+        group.throughput(Throughput::Bytes(fixture.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                serde_json::from_slice::<UpdateMessage>(black_box(fixture.as_bytes()))
+                    .expect("Fixture is a valid update")
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, parse_updates);
+criterion_main!(benches);
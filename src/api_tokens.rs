@@ -0,0 +1,243 @@
+use std::{io::ErrorKind, path::Path, time::Duration};
+
+use anyhow::Context;
+use chrono::{TimeDelta, Utc};
+use rand::{Rng, distr::Alphanumeric};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::config::AuthConfig;
+
+/// How often the running bot rereads the tokens storage.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+const TOKEN_LENGTH: usize = 64;
+const STORAGE_MODE: u32 = 0o600;
+
+/// Webhook secret tokens accepted by the bot. The first one is registered in telegram, the rest
+/// are left over from rotations and accepted until they retire.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ApiTokens(Vec<ApiToken>);
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ApiToken {
+    token: String,
+    /// Unix timestamp after which the token is no longer accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retire_at: Option<i64>,
+}
+
+impl ApiTokens {
+    /// Reads `auth.api_tokens_storage` when it exists, otherwise starts from `auth.api_token`.
+    pub async fn load(auth: &AuthConfig) -> anyhow::Result<Self> {
+        if let Some(file) = auth.api_tokens_storage.as_deref()
+            && file.exists()
+        {
+            return Self::open(file).await;
+        }
+
+        Ok(Self(
+            auth.api_token
+                .iter()
+                .map(|token| ApiToken {
+                    token: token.expose().to_string(),
+                    retire_at: None,
+                })
+                .collect(),
+        ))
+    }
+
+    pub async fn open(file: &Path) -> anyhow::Result<Self> {
+        let contents = tokio::fs::read(file)
+            .await
+            .with_context(|| format!("Failed to read api tokens {}", file.display()))?;
+
+        serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse api tokens {}", file.display()))
+    }
+
+    /// Writes a temporary file readable only by the owner and moves it over the storage, so the
+    /// tokens are never readable by others and the bot never reads a partly written file.
+    pub async fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let tmp_file = file.with_extension("tmp");
+        // A file left by a crash could have another mode, which opening it wouldn't change
+        match tokio::fs::remove_file(&tmp_file).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(STORAGE_MODE)
+            .open(&tmp_file)
+            .await
+            .with_context(|| format!("Failed to create {}", tmp_file.display()))?;
+        out.write_all(&serde_json::to_vec_pretty(self)?).await?;
+        out.sync_all().await?;
+        tokio::fs::rename(&tmp_file, file)
+            .await
+            .with_context(|| format!("Failed to replace {}", file.display()))?;
+
+        Ok(())
+    }
+
+    /// Token to register in telegram.
+    pub fn current(&self) -> Option<&str> {
+        self.0.first().map(|token| token.token.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Checks the token against every active token without short-circuiting, so the response
+    /// time doesn't tell how much of a token was guessed.
+    pub fn accepts(&self, token: &str) -> bool {
+        let now = Utc::now().timestamp();
+
+        self.0
+            .iter()
+            .filter(|active| active.retire_at.is_none_or(|retire_at| retire_at > now))
+            .fold(0u8, |matched, active| {
+                matched | active.token.as_bytes().ct_eq(token.as_bytes()).unwrap_u8()
+            })
+            == 1
+    }
+
+    /// Puts a freshly generated token first. The previous tokens retire after `grace_period`,
+    /// already retired ones are dropped.
+    pub fn rotate(&mut self, grace_period: TimeDelta) {
+        let now = Utc::now().timestamp();
+        let retire_at = now + grace_period.num_seconds();
+
+        self.0
+            .retain(|token| token.retire_at.is_none_or(|retire_at| retire_at > now));
+        for token in &mut self.0 {
+            token.retire_at = Some(token.retire_at.map_or(retire_at, |at| at.min(retire_at)));
+        }
+
+        self.0.insert(
+            0,
+            ApiToken {
+                token: generate_token(),
+                retire_at: None,
+            },
+        );
+    }
+}
+
+fn generate_token() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn make_tokens(tokens: &[(&str, Option<i64>)]) -> ApiTokens {
+        ApiTokens(
+            tokens
+                .iter()
+                .map(|&(token, retire_at)| ApiToken {
+                    token: token.to_string(),
+                    retire_at,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn accepts_only_active_tokens() {
+        let now = Utc::now().timestamp();
+        let tokens = make_tokens(&[
+            ("current", None),
+            ("retiring", Some(now + 60)),
+            ("retired", Some(now - 1)),
+        ]);
+
+        assert!(tokens.accepts("current"));
+        assert!(tokens.accepts("retiring"));
+        assert!(!tokens.accepts("retired"));
+        assert!(!tokens.accepts(""));
+        assert!(!tokens.accepts("curren"));
+        assert!(!tokens.accepts("currentt"));
+        assert!(!ApiTokens::default().accepts(""));
+    }
+
+    #[test]
+    fn rotation_keeps_previous_token_for_grace_period() {
+        let mut tokens = make_tokens(&[("old", None)]);
+        tokens.rotate(TimeDelta::hours(1));
+
+        let current = tokens.current().unwrap().to_string();
+        assert_eq!(current.len(), TOKEN_LENGTH);
+        assert_ne!(current, "old");
+        assert!(tokens.accepts(&current));
+        assert!(tokens.accepts("old"));
+
+        let retire_at = tokens.0[1].retire_at.unwrap();
+        assert!((retire_at - Utc::now().timestamp() - 3600).abs() <= 1);
+    }
+
+    #[test]
+    fn rotation_without_grace_period_retires_previous_token() {
+        let mut tokens = make_tokens(&[("old", None)]);
+        tokens.rotate(TimeDelta::zero());
+
+        assert!(tokens.accepts(tokens.current().unwrap()));
+        assert!(!tokens.accepts("old"));
+    }
+
+    #[test]
+    fn rotation_drops_retired_tokens_and_never_extends_grace() {
+        let now = Utc::now().timestamp();
+        let mut tokens = make_tokens(&[
+            ("current", None),
+            ("retiring", Some(now + 60)),
+            ("retired", Some(now - 1)),
+        ]);
+        tokens.rotate(TimeDelta::hours(1));
+
+        let retired: Vec<_> = tokens.0[1..]
+            .iter()
+            .map(|token| (token.token.as_str(), token.retire_at))
+            .collect();
+        assert_eq!(retired.len(), 2);
+        assert_eq!(retired[0].0, "current");
+        assert!(retired[0].1.unwrap() > now + 60);
+        assert_eq!(retired[1], ("retiring", Some(now + 60)));
+    }
+
+    #[tokio::test]
+    async fn saves_tokens_readable_only_by_owner() {
+        let dir = std::env::temp_dir().join(format!("anon-api-tokens-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("api_tokens.json");
+        // A leftover temporary file must not keep its mode
+        std::fs::write(file.with_extension("tmp"), "").unwrap();
+        std::fs::set_permissions(
+            file.with_extension("tmp"),
+            std::fs::Permissions::from_mode(0o644),
+        )
+        .unwrap();
+
+        let mut tokens = make_tokens(&[("old", None)]);
+        tokens.rotate(TimeDelta::hours(1));
+        tokens.save(&file).await.unwrap();
+
+        let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, STORAGE_MODE);
+        let saved = ApiTokens::open(&file).await.unwrap();
+        assert_eq!(saved.current(), tokens.current());
+        assert!(saved.accepts("old"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
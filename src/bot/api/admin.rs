@@ -0,0 +1,50 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Response, StatusCode, header::CONTENT_TYPE},
+    response::IntoResponse,
+};
+
+use crate::{
+    log::{error, info},
+    metrics::{self, REGISTERED_CHATS, REGISTERED_USERS},
+    state::AppState,
+};
+
+pub async fn metrics(State(state): State<AppState>) -> Response<Body> {
+    let (chats_count, users_count) = state.chats().counts().await;
+    REGISTERED_CHATS.set(chats_count as i64);
+    REGISTERED_USERS.set(users_count as i64);
+
+    match metrics::encode() {
+        Ok(body) => ([(CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response(),
+        Err(err) => {
+            error!("Failed to encode metrics: {err:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn healthz() -> &'static str {
+    "OK"
+}
+
+pub async fn readyz(State(state): State<AppState>) -> Response<Body> {
+    let mut problems = vec![];
+
+    if let Err(err) = state.check_storage_writable().await {
+        problems.push(format!("{err:#}"));
+    }
+    if !state.tg_client().is_api_available() {
+        problems.push("Last telegram api call failed".to_string());
+    }
+
+    if problems.is_empty() {
+        return "OK".into_response();
+    }
+
+    let body = problems.join("\n");
+    info!("Not ready: {body}");
+
+    (StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+}
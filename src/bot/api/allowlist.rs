@@ -0,0 +1,54 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    bot::api::headers::{ClientIp, FORWARDED_FOR_HEADER_NAME},
+    log::warn,
+    metrics::WEBHOOK_REJECTED,
+    state::AppState,
+};
+
+/// Rejects webhook requests from outside of `http.allowed_ips`. Requests whose client ip is
+/// unknown, e.g. from a unix socket proxy which doesn't set `X-Forwarded-For`, are rejected too.
+pub async fn allow_listed_ips(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    let allowed_ips = &state.config().http.allowed_ips;
+    let allowed = client_ip.is_some_and(|ip| allowed_ips.iter().any(|net| net.contains(&ip)));
+    if !allowed {
+        warn!(
+            "Rejected webhook request from {}",
+            client_ip.map_or_else(|| "unknown client".to_string(), |ip| ip.to_string())
+        );
+        WEBHOOK_REJECTED.inc();
+
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Rejects requests which don't come straight from the local host. Requests passed on by a
+/// proxy, even a local one, may come from anywhere, so unix socket peers and requests with
+/// `X-Forwarded-For` are rejected too.
+pub async fn local_clients(request: Request, next: Next) -> Response {
+    let is_local = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .is_some_and(|ConnectInfo(addr)| addr.ip().to_canonical().is_loopback())
+        && !request.headers().contains_key(FORWARDED_FOR_HEADER_NAME);
+    if !is_local {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    next.run(request).await
+}
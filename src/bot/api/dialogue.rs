@@ -0,0 +1,448 @@
+use crate::{
+    bot::{
+        api::{
+            check_document, check_target_chat,
+            entities::{
+                CallbackQuery, ChatType, InlineKeyboardButton, InlineKeyboardMarkup, Message,
+                WebhookResponse,
+            },
+            make_answer_callback_query, make_answer_callback_query_alert,
+            make_bot_chat_selection_message, make_bot_text_message, post_anonymously,
+        },
+        callback_data::CallbackData,
+        publisher,
+    },
+    dialogues::{DialogueState, Draft},
+    log::{error, info},
+    outbox::OutgoingContent,
+    state::AppState,
+};
+
+const STALE_DRAFT_TEXT: &str =
+    "Этот черновик уже неактуален: его отправили, отменили или написали новое сообщение";
+
+/// Handles a message in private chat which isn't a command, according to the user's dialogue.
+pub(super) async fn handle_private_message(
+    state: &AppState,
+    message: &Message<'_>,
+    user_id: i64,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    match state.dialogues().get(user_id).await {
+        DialogueState::Idle | DialogueState::ChoosingChat { .. } => {
+            show_chat_selection(state, user_id).await.map(Some)
+        }
+        DialogueState::Composing { chat_id } => compose(state, message, user_id, chat_id).await,
+        // A new message replaces the draft
+        DialogueState::Confirming { draft } => {
+            compose(state, message, user_id, draft.chat_id).await
+        }
+    }
+}
+
+/// Shows the chat selection. A draft being moved to another chat keeps waiting meanwhile, and
+/// the selected chat stays selected until another one is picked.
+pub(super) async fn show_chat_selection(
+    state: &AppState,
+    user_id: i64,
+) -> anyhow::Result<WebhookResponse> {
+    if let DialogueState::Idle = state.dialogues().get(user_id).await {
+        state
+            .dialogues()
+            .set(user_id, DialogueState::ChoosingChat { draft: None })
+            .await;
+        state.save_dialogues().await?;
+    }
+
+    // Private chats share ids with their users
+    let payload = make_bot_chat_selection_message(state, user_id, user_id).await?;
+
+    Ok(WebhookResponse::new("sendMessage", payload))
+}
+
+/// Makes the chat the target of the user's messages. A draft being moved goes there and is shown
+/// for confirmation again.
+pub(super) async fn select_chat(
+    state: &AppState,
+    user_id: i64,
+    chat_id: i64,
+) -> anyhow::Result<WebhookResponse> {
+    match state.dialogues().get(user_id).await {
+        DialogueState::ChoosingChat { draft: Some(draft) }
+        | DialogueState::Confirming { draft } => {
+            let draft = Draft { chat_id, ..draft };
+
+            show_new_draft(state, user_id, draft).await
+        }
+        DialogueState::Idle
+        | DialogueState::ChoosingChat { draft: None }
+        | DialogueState::Composing { .. } => {
+            state
+                .dialogues()
+                .set(user_id, DialogueState::Composing { chat_id })
+                .await;
+            state.save_dialogues().await?;
+
+            let title = state
+                .chats()
+                .get_chat(chat_id)
+                .await
+                .and_then(|chat| chat.title.clone());
+            let text = match title {
+                Some(title) => format!("Напиши текст сообщения для чата \"{title}\""),
+                None => "Напиши текст сообщения".to_string(),
+            };
+
+            Ok(WebhookResponse::new(
+                "sendMessage",
+                make_bot_text_message(user_id, &text),
+            ))
+        }
+    }
+}
+
+/// Sends the message to the chat, or makes it a draft for users who confirm their messages.
+/// Messages the chat refuses leave the dialogue as it is.
+async fn compose(
+    state: &AppState,
+    message: &Message<'_>,
+    user_id: i64,
+    chat_id: i64,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let mixing = match check_target_chat(state, user_id, chat_id).await {
+        Ok(mixing) => mixing,
+        Err(refusal) => return Ok(Some(refusal)),
+    };
+    if let Some(text) = check_document(message) {
+        let payload = make_bot_text_message(user_id, text);
+
+        return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+    }
+    let contents = OutgoingContent::from_message(message);
+    if contents.is_empty() {
+        return Ok(None);
+    }
+
+    if state.dialogues().confirms(user_id).await {
+        let draft = Draft {
+            chat_id,
+            source_message_id: message.message_id,
+            contents,
+        };
+
+        return show_new_draft(state, user_id, draft).await.map(Some);
+    }
+
+    // A draft left from before /confirm was turned off is dropped, the message is sent instead
+    state
+        .dialogues()
+        .set(user_id, DialogueState::Composing { chat_id })
+        .await;
+    state.save_dialogues().await?;
+
+    post_anonymously(
+        state,
+        user_id,
+        message.message_id,
+        chat_id,
+        mixing,
+        contents,
+    )
+    .await
+}
+
+/// Resets the dialogue, dropping the draft and the selected chat.
+pub(super) async fn handle_cancel_command(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if !matches!(message.chat.chat_type, ChatType::Private) {
+        return Ok(None);
+    }
+    let Some(user) = message.from.as_ref() else {
+        return Ok(None);
+    };
+
+    let text = match state.dialogues().get(user.id).await {
+        DialogueState::Idle => "Нечего отменять",
+        DialogueState::ChoosingChat { draft: None } | DialogueState::Composing { .. } => {
+            "Выбор чата отменён. Отправь /send, чтобы выбрать чат"
+        }
+        DialogueState::ChoosingChat { draft: Some(_) } | DialogueState::Confirming { .. } => {
+            "Сообщение не отправлено, выбор чата отменён. Отправь /send, чтобы выбрать чат"
+        }
+    };
+    state.dialogues().set(user.id, DialogueState::Idle).await;
+    state.save_dialogues().await?;
+    let payload = make_bot_text_message(message.chat.id, text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Toggles the confirmation step for the user's messages.
+pub(super) async fn handle_confirm_command(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if !matches!(message.chat.chat_type, ChatType::Private) {
+        return Ok(None);
+    }
+    let Some(user) = message.from.as_ref() else {
+        return Ok(None);
+    };
+
+    let confirms = state.dialogues().toggle_confirmation(user.id).await;
+    state.save_dialogues().await?;
+
+    let text = match confirms {
+        true => {
+            "Теперь я буду показывать, как сообщение будет выглядеть в чате, и отправлять его только после подтверждения. Отправь /confirm ещё раз, чтобы отправлять сразу"
+        }
+        false => {
+            "Теперь сообщения отправляются сразу. Отправь /confirm, чтобы снова их подтверждать"
+        }
+    };
+    let payload = make_bot_text_message(message.chat.id, text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Keeps the message as the user's draft and asks to confirm it.
+async fn show_new_draft(
+    state: &AppState,
+    user_id: i64,
+    draft: Draft,
+) -> anyhow::Result<WebhookResponse> {
+    state
+        .dialogues()
+        .set(
+            user_id,
+            DialogueState::Confirming {
+                draft: draft.clone(),
+            },
+        )
+        .await;
+    state.save_dialogues().await?;
+
+    show_draft(state, user_id, &draft).await
+}
+
+/// Shows the draft the way it would be published, followed by the confirmation buttons. Drafts
+/// which can't be published, such as documents that can't be stripped, are dropped right away.
+async fn show_draft(
+    state: &AppState,
+    user_id: i64,
+    draft: &Draft,
+) -> anyhow::Result<WebhookResponse> {
+    if let Err(err) = publisher::preview(state, user_id, &draft.contents).await {
+        info!("Failed to preview draft of user {user_id}: {err:#}");
+        state
+            .dialogues()
+            .set(
+                user_id,
+                DialogueState::Composing {
+                    chat_id: draft.chat_id,
+                },
+            )
+            .await;
+        state.save_dialogues().await?;
+        let payload = make_bot_text_message(user_id, publisher::publish_failed_text(&err));
+
+        return Ok(WebhookResponse::new("sendMessage", payload));
+    }
+
+    let title = state
+        .chats()
+        .get_chat(draft.chat_id)
+        .await
+        .and_then(|chat| chat.title.clone())
+        .unwrap_or_else(|| "без названия".to_string());
+    let button = |text: String, data: CallbackData| {
+        anyhow::Ok(InlineKeyboardButton {
+            text,
+            callback_data: state.callback_signer().encode(data, user_id)?,
+        })
+    };
+    let keyboard = InlineKeyboardMarkup {
+        inline_keyboard: vec![
+            vec![button(
+                format!("Отправить в «{title}»"),
+                CallbackData::ConfirmDraft(draft.source_message_id, draft.chat_id),
+            )?],
+            vec![
+                button(
+                    "Сменить чат".to_string(),
+                    CallbackData::ChangeDraftChat(draft.source_message_id),
+                )?,
+                button(
+                    "Отмена".to_string(),
+                    CallbackData::CancelDraft(draft.source_message_id),
+                )?,
+            ],
+        ],
+    };
+
+    Ok(WebhookResponse::new(
+        "sendMessage",
+        serde_json::json!({
+            "chat_id": user_id,
+            "text": format!("Так сообщение появится в чате «{title}». Отправить?"),
+            "reply_markup": keyboard,
+        }),
+    ))
+}
+
+/// Carries the user's edit over to their draft. Returns whether the message is a draft.
+pub(super) async fn edit_draft(
+    state: &AppState,
+    user_id: i64,
+    source_message_id: i32,
+    contents: Vec<OutgoingContent>,
+) -> anyhow::Result<bool> {
+    match state.dialogues().get(user_id).await {
+        DialogueState::Confirming { draft } if draft.source_message_id == source_message_id => {
+            // The user confirms the edited draft anew
+            let draft = Draft { contents, ..draft };
+            let reply = show_new_draft(state, user_id, draft).await?;
+            state.tg_client().send_reply(&reply).await;
+        }
+        DialogueState::ChoosingChat { draft: Some(draft) }
+            if draft.source_message_id == source_message_id =>
+        {
+            let draft = Draft { contents, ..draft };
+            state
+                .dialogues()
+                .set(user_id, DialogueState::ChoosingChat { draft: Some(draft) })
+                .await;
+            state.save_dialogues().await?;
+        }
+        _ => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+pub(super) async fn handle_confirm_button_clicked(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+    source_message_id: i32,
+    chat_id: i64,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user_id = query.from.id;
+    // The draft could be moved to another chat since the buttons were shown
+    let draft = match state.dialogues().get(user_id).await {
+        DialogueState::Confirming { draft }
+            if draft.source_message_id == source_message_id && draft.chat_id == chat_id =>
+        {
+            draft
+        }
+        _ => {
+            return Ok(Some(make_answer_callback_query_alert(
+                &query.id,
+                STALE_DRAFT_TEXT,
+            )));
+        }
+    };
+
+    state
+        .tg_client()
+        .answer_callback_query(&query.id, None)
+        .await;
+    let mixing = match check_target_chat(state, user_id, chat_id).await {
+        Ok(mixing) => mixing,
+        Err(refusal) => return Ok(Some(refusal)),
+    };
+
+    state
+        .dialogues()
+        .set(user_id, DialogueState::Composing { chat_id })
+        .await;
+    state.save_dialogues().await?;
+    close_prompt(state, query, "Сообщение подтверждено").await;
+
+    post_anonymously(
+        state,
+        user_id,
+        source_message_id,
+        chat_id,
+        mixing,
+        draft.contents,
+    )
+    .await
+}
+
+/// Shows the chat selection, picking a chat there moves the draft to it.
+pub(super) async fn handle_change_chat_button_clicked(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+    source_message_id: i32,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user_id = query.from.id;
+    let draft = match state.dialogues().get(user_id).await {
+        DialogueState::Confirming { draft } if draft.source_message_id == source_message_id => {
+            draft
+        }
+        _ => {
+            return Ok(Some(make_answer_callback_query_alert(
+                &query.id,
+                STALE_DRAFT_TEXT,
+            )));
+        }
+    };
+
+    state
+        .dialogues()
+        .set(user_id, DialogueState::ChoosingChat { draft: Some(draft) })
+        .await;
+    state.save_dialogues().await?;
+    state
+        .tg_client()
+        .answer_callback_query(&query.id, None)
+        .await;
+    close_prompt(state, query, "Выбери, в какой чат отправить сообщение").await;
+
+    show_chat_selection(state, user_id).await.map(Some)
+}
+
+/// Drops the draft, the chat stays selected.
+pub(super) async fn handle_cancel_button_clicked(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+    source_message_id: i32,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let dialogue = state.dialogues().get(query.from.id).await;
+    let Some(draft) = dialogue.draft(source_message_id) else {
+        return Ok(Some(make_answer_callback_query_alert(
+            &query.id,
+            STALE_DRAFT_TEXT,
+        )));
+    };
+
+    state
+        .dialogues()
+        .set(
+            query.from.id,
+            DialogueState::Composing {
+                chat_id: draft.chat_id,
+            },
+        )
+        .await;
+    state.save_dialogues().await?;
+    close_prompt(state, query, "Сообщение не отправлено").await;
+
+    Ok(Some(make_answer_callback_query(&query.id)))
+}
+
+/// Replaces the confirmation prompt with the outcome, so its buttons can't be clicked again.
+async fn close_prompt(state: &AppState, query: &CallbackQuery<'_>, text: &str) {
+    let Some(prompt) = query.message.as_deref() else {
+        return;
+    };
+
+    if let Err(err) = state
+        .tg_client()
+        .edit_message_text(prompt.chat.id, prompt.message_id, text)
+        .await
+    {
+        error!("Failed to close draft prompt: {err:#}");
+    }
+}
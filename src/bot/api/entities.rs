@@ -1,12 +1,49 @@
-use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub type ChatId = i64;
 
+/// Incoming update. Strings borrow from the request body where JSON escaping allows it.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct UpdateMessage {
+pub struct UpdateMessage<'a> {
     pub update_id: i64,
-    pub message: Option<Message>,
-    pub callback_query: Option<CallbackQuery>,
+    #[serde(borrow)]
+    pub message: Option<Message<'a>>,
+    /// Sent when a message is edited, with its new contents
+    #[serde(borrow)]
+    pub edited_message: Option<Message<'a>>,
+    #[serde(borrow)]
+    pub callback_query: Option<CallbackQuery<'a>>,
+}
+
+/// Deserializes an optional string, borrowing it from the input when it has no escapes. Serde
+/// borrows only into a bare `Cow`, never through an `Option`.
+fn borrow_optional_str<'de: 'a, 'a, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Cow<'a, str>>, D::Error> {
+    #[derive(Deserialize)]
+    struct Borrowed<'a>(#[serde(borrow)] Cow<'a, str>);
+
+    Ok(Option::<Borrowed<'a>>::deserialize(deserializer)?.map(|Borrowed(value)| value))
+}
+
+impl UpdateMessage<'_> {
+    /// User the update comes from, or the chat for messages without one.
+    pub fn sender_id(&self) -> Option<i64> {
+        let message = self.message.as_ref().or(self.edited_message.as_ref());
+
+        match (message, &self.callback_query) {
+            (Some(message), _) => Some(
+                message
+                    .from
+                    .as_ref()
+                    .map_or(message.chat.id, |user| user.id),
+            ),
+            (None, Some(callback_query)) => Some(callback_query.from.id),
+            (None, None) => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -16,33 +53,148 @@ pub struct WebhookResponse {
     pub params: serde_json::Value,
 }
 
+impl WebhookResponse {
+    pub fn new(method: &str, params: serde_json::Value) -> Self {
+        Self {
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiResponse<T> {
+    pub result: T,
+}
+
+/// Message the bot has sent, only its id is of interest.
+#[derive(Debug, Deserialize)]
+pub struct SentMessage {
+    pub message_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct File {
+    pub file_id: String,
+    /// Set while the file can be downloaded, for an hour at least
+    pub file_path: Option<String>,
+    pub file_size: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookInfo {
+    pub url: String,
+    pub has_custom_certificate: bool,
+    pub pending_update_count: i64,
+    pub ip_address: Option<String>,
+    pub last_error_date: Option<i64>,
+    pub last_error_message: Option<String>,
+    pub last_synchronization_error_date: Option<i64>,
+    pub max_connections: Option<i64>,
+    pub allowed_updates: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub parameters: Option<ResponseParameters>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResponseParameters {
+    pub retry_after: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Message {
+pub struct Message<'a> {
     pub message_id: i32,
-    pub from: Option<User>,
-    pub chat: Chat,
-    pub text: Option<String>,
+    #[serde(borrow)]
+    pub from: Option<User<'a>>,
+    #[serde(borrow)]
+    pub chat: Chat<'a>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub text: Option<Cow<'a, str>>,
     pub date: i32,
-    pub photo: Option<Vec<PhotoSize>>,
-    pub animation: Option<Animation>,
-    pub sticker: Option<Sticker>,
-    pub caption: Option<String>,
-    pub callback_query: Option<CallbackQuery>,
+    #[serde(borrow)]
+    pub photo: Option<Vec<PhotoSize<'a>>>,
+    #[serde(borrow)]
+    pub animation: Option<Animation<'a>>,
+    /// Also set for animations, for clients which don't know them
+    #[serde(borrow)]
+    pub document: Option<Document<'a>>,
+    #[serde(borrow)]
+    pub sticker: Option<Sticker<'a>>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub caption: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    pub reply_to_message: Option<Box<Message<'a>>>,
+    #[serde(borrow)]
+    pub callback_query: Option<CallbackQuery<'a>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct User {
+pub struct User<'a> {
     pub id: i64,
     pub is_bot: bool,
-    pub username: Option<String>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub username: Option<Cow<'a, str>>,
+}
+
+impl User<'_> {
+    pub fn into_owned(self) -> User<'static> {
+        User {
+            id: self.id,
+            is_bot: self.is_bot,
+            username: self
+                .username
+                .map(|username| Cow::Owned(username.into_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMember {
+    pub status: ChatMemberStatus,
+    /// Only set for restricted members
+    pub is_member: Option<bool>,
+}
+
+impl ChatMember {
+    pub fn is_member(&self) -> bool {
+        match self.status {
+            ChatMemberStatus::Creator
+            | ChatMemberStatus::Administrator
+            | ChatMemberStatus::Member => true,
+            ChatMemberStatus::Restricted => self.is_member.unwrap_or(false),
+            ChatMemberStatus::Left | ChatMemberStatus::Kicked => false,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self.status,
+            ChatMemberStatus::Creator | ChatMemberStatus::Administrator
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatMemberStatus {
+    Creator,
+    Administrator,
+    Member,
+    Restricted,
+    Left,
+    Kicked,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Chat {
+pub struct Chat<'a> {
     pub id: ChatId,
     #[serde(rename = "type")]
     pub chat_type: ChatType,
-    pub title: Option<String>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub title: Option<Cow<'a, str>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,35 +207,55 @@ pub enum ChatType {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct PhotoSize {
-    pub file_id: String,
-    pub file_unique_id: String,
+pub struct PhotoSize<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub file_unique_id: Cow<'a, str>,
     pub width: i64,
     pub height: i64,
     pub file_size: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Animation {
-    pub file_id: String,
-    pub file_unique_id: String,
+pub struct Animation<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub file_unique_id: Cow<'a, str>,
     pub width: i64,
     pub height: i64,
     pub duration: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Sticker {
-    pub file_id: String,
+pub struct Document<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub file_name: Option<Cow<'a, str>>,
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub mime_type: Option<Cow<'a, str>>,
+    pub file_size: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sticker<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CallbackQuery {
-    pub id: String,
-    pub from: User,
-    pub message: Option<Box<Message>>, // This is MaybeInaccessibleMessage in docs but for now it's fields are almost common with regular
-    #[serde(with = "callback_data_as_string_opt")]
-    pub data: Option<CallbackData>,
+pub struct CallbackQuery<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub from: User<'a>,
+    #[serde(borrow)]
+    pub message: Option<Box<Message<'a>>>, // This is MaybeInaccessibleMessage in docs but for now it's fields are almost common with regular
+    /// Signed with [`crate::bot::callback_data::CallbackSigner`]
+    #[serde(default, borrow, deserialize_with = "borrow_optional_str")]
+    pub data: Option<Cow<'a, str>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -94,14 +266,7 @@ pub struct InlineKeyboardMarkup {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InlineKeyboardButton {
     pub text: String,
-    #[serde(with = "callback_data_as_string")]
-    pub callback_data: CallbackData,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub enum CallbackData {
-    ActionSend,
-    SendTo(i64),
+    pub callback_data: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -132,97 +297,50 @@ pub struct SendStickerPayload<'a> {
     pub sticker: &'a str,
 }
 
-mod callback_data_as_string {
-    use serde::{Deserializer, Serializer, de::Visitor, ser::Error as _};
-
-    use super::CallbackData;
-
-    pub fn serialize<S: Serializer>(
-        callback_data: &CallbackData,
-        serializer: S,
-    ) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(
-            &serde_json::to_string(callback_data).map_err(|err| {
-                S::Error::custom(format!("Failed to serialize callback data: {err}"))
-            })?,
-        )
-    }
-
-    pub fn deserialize<'de, D: Deserializer<'de>>(
-        deserializer: D,
-    ) -> Result<CallbackData, D::Error> {
-        struct StrVisitor;
-
-        impl<'de> Visitor<'de> for StrVisitor {
-            type Value = CallbackData;
-
-            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(f, "a string")
-            }
-
-            fn visit_str<E>(self, v: &str) -> Result<CallbackData, E>
-            where
-                E: serde::de::Error,
-            {
-                serde_json::from_str(v)
-                    .map_err(|err| E::custom(format!("Failed to deserialize callback data: {err}")))
-            }
-        }
-
-        deserializer.deserialize_string(StrVisitor)
-    }
-}
-
-mod callback_data_as_string_opt {
-    use serde::{Deserializer, Serializer, de::Visitor};
-
-    use super::CallbackData;
-
-    pub fn serialize<S: Serializer>(
-        callback_data: &Option<CallbackData>,
-        serializer: S,
-    ) -> Result<S::Ok, S::Error> {
-        match callback_data.as_ref() {
-            Some(data) => super::callback_data_as_string::serialize(data, serializer),
-            None => serializer.serialize_none(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrows_optional_strings() {
+        let body = r#"{
+            "update_id": 1,
+            "message": {
+                "message_id": 2,
+                "date": 0,
+                "from": {"id": 3, "is_bot": false, "username": "user"},
+                "chat": {"id": -4, "type": "supergroup", "title": "Chat"},
+                "text": "text",
+                "caption": "caption",
+                "document": {"file_id": "id", "file_name": "a.pdf", "mime_type": "application/pdf"}
+            },
+            "callback_query": {"id": "5", "from": {"id": 3, "is_bot": false}, "data": "data"}
+        }"#;
+        let update: UpdateMessage<'_> = serde_json::from_str(body).unwrap();
+        let message = update.message.unwrap();
+        let document = message.document.unwrap();
+        let callback_query = update.callback_query.unwrap();
+
+        for value in [
+            message.text,
+            message.caption,
+            message.from.unwrap().username,
+            message.chat.title,
+            document.file_name,
+            document.mime_type,
+            callback_query.data,
+        ] {
+            assert!(matches!(value, Some(Cow::Borrowed(_))), "{value:?}");
         }
+        assert!(callback_query.from.username.is_none());
     }
 
-    pub fn deserialize<'de, D: Deserializer<'de>>(
-        deserializer: D,
-    ) -> Result<Option<CallbackData>, D::Error> {
-        struct OptStrVisitor;
-
-        impl<'de> Visitor<'de> for OptStrVisitor {
-            type Value = Option<CallbackData>;
-
-            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(f, "a string")
-            }
-
-            fn visit_str<E>(self, v: &str) -> Result<Option<CallbackData>, E>
-            where
-                E: serde::de::Error,
-            {
-                serde_json::from_str(v)
-                    .map_err(|err| E::custom(format!("Failed to deserialize callback data: {err}")))
-            }
-
-            fn visit_some<D>(self, deserializer: D) -> Result<Option<CallbackData>, D::Error>
-            where
-                D: Deserializer<'de>,
-            {
-                super::callback_data_as_string::deserialize(deserializer).map(Some)
-            }
-
-            fn visit_none<E>(self) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                Ok(None)
-            }
-        }
+    #[test]
+    fn unescapes_optional_strings() {
+        let body = r#"{"message_id": 1, "date": 0, "chat": {"id": 1, "type": "private"}, "text": "a\"bé"}"#;
+        let message: Message<'_> = serde_json::from_str(body).unwrap();
 
-        deserializer.deserialize_option(OptStrVisitor)
+        assert_eq!(message.text.as_deref(), Some("a\"bé"));
+        assert!(message.caption.is_none());
     }
 }
@@ -0,0 +1,238 @@
+use crate::{
+    bot::api::{
+        entities::{ChatType, Message, WebhookResponse},
+        make_bot_text_message,
+    },
+    chats::{DEFAULT_MIN_SENDERS, Mixing},
+    state::AppState,
+};
+
+/// Longest delay or batch interval of mixing, a day.
+const MAX_MIXING_MINUTES: u64 = 24 * 60;
+
+/// Sends group admins a `t.me/<bot>?start=c_<token>` link, which lets members join the chat
+/// without announcing themselves with /send.
+pub(super) async fn handle_link_command(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user = match message.from.as_ref() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(None),
+    };
+    if let Some(reply) = check_group_admin(state, message, user.id).await? {
+        return Ok(Some(reply));
+    }
+
+    if state.chats().add_chat(&message.chat).await {
+        state.save_chats().await?;
+    }
+
+    let bot_username = state.tg_client().bot_username().await?;
+    let link_payload = state.chat_link_signer().encode(message.chat.id);
+    let payload = make_bot_text_message(
+        message.chat.id,
+        &format!(
+            "Чтобы отправлять анонимные сообщения в этот чат, перейди по ссылке: https://t.me/{bot_username}?start={link_payload}"
+        ),
+    );
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Toggles silent registration: /send in the chat gets deleted and confirmed in private instead
+/// of announcing the sender to everyone.
+pub(super) async fn handle_silent_command(
+    state: &AppState,
+    message: &Message<'_>,
+    argument: &str,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user = match message.from.as_ref() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(None),
+    };
+    if let Some(reply) = check_group_admin(state, message, user.id).await? {
+        return Ok(Some(reply));
+    }
+
+    let silent = match argument {
+        "on" => true,
+        "off" => false,
+        _ => {
+            let silent = state
+                .chats()
+                .get_chat(message.chat.id)
+                .await
+                .is_some_and(|chat| chat.settings.silent);
+            let text = format!(
+                "Тихая регистрация {}. Используй /anonsilent on или /anonsilent off, чтобы изменить",
+                if silent {
+                    "включена"
+                } else {
+                    "выключена"
+                }
+            );
+            let payload = make_bot_text_message(message.chat.id, &text);
+
+            return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+        }
+    };
+
+    state
+        .chats()
+        .update_settings(&message.chat, |settings| settings.silent = silent)
+        .await;
+    state.save_chats().await?;
+
+    let text = match silent {
+        true => {
+            "Теперь команда /send удаляется из чата, а подтверждение приходит в личные сообщения. Боту нужно право удалять сообщения"
+        }
+        false => "Теперь новые отправители снова объявляются в чате",
+    };
+    let payload = make_bot_text_message(message.chat.id, text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Sets how many members must be able to post before messages to the chat are forwarded.
+pub(super) async fn handle_min_senders_command(
+    state: &AppState,
+    message: &Message<'_>,
+    argument: &str,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user = match message.from.as_ref() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(None),
+    };
+    if let Some(reply) = check_group_admin(state, message, user.id).await? {
+        return Ok(Some(reply));
+    }
+
+    let Some(min_senders) = argument.parse::<usize>().ok().filter(|&k| k >= 1) else {
+        let (members, min_senders) = match state.chats().get_chat(message.chat.id).await {
+            Some(chat) => (chat.members.len(), chat.settings.min_senders),
+            None => (0, DEFAULT_MIN_SENDERS),
+        };
+        let text = format!(
+            "Сообщения пересылаются, когда анонимно писать могут хотя бы {min_senders} участников, сейчас их {members}. Используй /anonmin <число>, чтобы изменить"
+        );
+        let payload = make_bot_text_message(message.chat.id, &text);
+
+        return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+    };
+
+    state
+        .chats()
+        .update_settings(&message.chat, |settings| settings.min_senders = min_senders)
+        .await;
+    state.save_chats().await?;
+
+    let text = format!(
+        "Теперь сообщения пересылаются, когда анонимно писать могут хотя бы {min_senders} участников"
+    );
+    let payload = make_bot_text_message(message.chat.id, &text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Sets up mixing: `/anonmix delay <minutes>`, `/anonmix batch <minutes>` or `/anonmix off`.
+pub(super) async fn handle_mixing_command(
+    state: &AppState,
+    message: &Message<'_>,
+    argument: &str,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user = match message.from.as_ref() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(None),
+    };
+    if let Some(reply) = check_group_admin(state, message, user.id).await? {
+        return Ok(Some(reply));
+    }
+
+    let Some(mixing) = parse_mixing(argument) else {
+        let mixing = state
+            .chats()
+            .get_chat(message.chat.id)
+            .await
+            .map(|chat| chat.settings.mixing)
+            .unwrap_or_default();
+        let text = format!(
+            "{}. Используй /anonmix delay <минуты>, /anonmix batch <минуты> или /anonmix off, чтобы изменить",
+            describe_mixing(mixing)
+        );
+        let payload = make_bot_text_message(message.chat.id, &text);
+
+        return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+    };
+
+    state
+        .chats()
+        .update_settings(&message.chat, |settings| settings.mixing = mixing)
+        .await;
+    state.save_chats().await?;
+
+    let payload = make_bot_text_message(message.chat.id, &describe_mixing(mixing));
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+fn parse_mixing(argument: &str) -> Option<Mixing> {
+    let mut words = argument.split_whitespace();
+    let mode = words.next()?;
+    let minutes = words
+        .next()
+        .and_then(|minutes| minutes.parse::<u64>().ok())
+        .filter(|minutes| (1..=MAX_MIXING_MINUTES).contains(minutes));
+    if words.next().is_some() {
+        return None;
+    }
+
+    match (mode, minutes) {
+        ("off", None) => Some(Mixing::Off),
+        ("delay", Some(minutes)) => Some(Mixing::Delay {
+            max_delay_secs: minutes * 60,
+        }),
+        ("batch", Some(minutes)) => Some(Mixing::Batch {
+            interval_secs: minutes * 60,
+        }),
+        _ => None,
+    }
+}
+
+fn describe_mixing(mixing: Mixing) -> String {
+    match mixing {
+        Mixing::Off => "Сообщения публикуются сразу".to_string(),
+        Mixing::Delay { max_delay_secs } => format!(
+            "Сообщения публикуются со случайной задержкой до {} мин.",
+            max_delay_secs / 60
+        ),
+        Mixing::Batch { interval_secs } => format!(
+            "Сообщения публикуются пачками раз в {} мин. в случайном порядке",
+            interval_secs / 60
+        ),
+    }
+}
+
+/// Returns the reply for anyone who isn't an admin of a group.
+async fn check_group_admin(
+    state: &AppState,
+    message: &Message<'_>,
+    user_id: i64,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let text = if matches!(message.chat.chat_type, ChatType::Private) {
+        "Эта команда работает только в групповых чатах"
+    } else if !state
+        .tg_client()
+        .get_chat_member(message.chat.id, user_id)
+        .await?
+        .is_admin()
+    {
+        "Эта команда доступна только администраторам чата"
+    } else {
+        return Ok(None);
+    };
+    let payload = make_bot_text_message(message.chat.id, text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
@@ -1,13 +1,19 @@
-use std::ops::Deref;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
+};
 
 use axum::{
-    extract::OptionalFromRequestParts,
-    http::{StatusCode, request::Parts},
+    extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts},
+    http::{HeaderMap, StatusCode, request::Parts},
 };
+use ipnet::IpNet;
 
-use crate::log::error;
+use crate::{log::error, state::AppState};
 
 const API_SECRET_TOKEN_HEADER_NAME: &str = "X-Telegram-Bot-Api-Secret-Token";
+pub const FORWARDED_FOR_HEADER_NAME: &str = "X-Forwarded-For";
 
 pub struct ApiSecretToken(String);
 
@@ -39,3 +45,186 @@ impl<S: Send + Sync> OptionalFromRequestParts<S> for ApiSecretToken {
             })
     }
 }
+
+/// Ip of the client that sent the request. `X-Forwarded-For` is honoured only for requests from
+/// trusted proxies and unix sockets. `None` means the client is unknown.
+///
+/// IPv4-mapped IPv6 addresses, which IPv4 clients of a dual-stack listener have, are converted
+/// to IPv4, so they match IPv4 nets.
+pub struct ClientIp(pub Option<IpAddr>);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let peer_addr = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+
+        Ok(Self::resolve(
+            peer_addr,
+            &parts.headers,
+            &state.config().http.trusted_proxies,
+        ))
+    }
+}
+
+impl ClientIp {
+    /// `peer_addr` is `None` for unix sockets.
+    fn resolve(
+        peer_addr: Option<SocketAddr>,
+        headers: &HeaderMap,
+        trusted_proxies: &[IpNet],
+    ) -> Self {
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+        let peer_ip = peer_addr.map(|addr| addr.ip().to_canonical());
+        if peer_ip.as_ref().is_some_and(|ip| !is_trusted(ip)) {
+            return Self(peer_ip);
+        }
+
+        let forwarded_ips = headers
+            .get_all(FORWARDED_FOR_HEADER_NAME)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+            .map(|ip| ip.trim().parse::<IpAddr>().ok().map(|ip| ip.to_canonical()))
+            .collect::<Vec<_>>();
+        if forwarded_ips.is_empty() {
+            return Self(peer_ip);
+        }
+
+        // Every proxy appends the address it got the request from, so the rightmost untrusted
+        // address is the client. Anything to the left of it may be forged.
+        let client_ip = forwarded_ips
+            .iter()
+            .rev()
+            .find(|ip| !ip.as_ref().is_some_and(is_trusted))
+            .unwrap_or(&forwarded_ips[0]);
+
+        Self(*client_ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(
+        peer: Option<&str>,
+        forwarded_for: &[&str],
+        trusted_proxies: &[&str],
+    ) -> Option<IpAddr> {
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append(FORWARDED_FOR_HEADER_NAME, value.parse().unwrap());
+        }
+        let trusted_proxies = trusted_proxies
+            .iter()
+            .map(|net| net.parse().unwrap())
+            .collect::<Vec<IpNet>>();
+
+        ClientIp::resolve(
+            peer.map(|addr| addr.parse().unwrap()),
+            &headers,
+            &trusted_proxies,
+        )
+        .0
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        assert_eq!(
+            resolve(Some("192.0.2.1:1234"), &["203.0.113.7"], &[]),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve(Some("192.0.2.1:1234"), &["203.0.113.7"], &["127.0.0.0/8"]),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn picks_rightmost_untrusted_address() {
+        let trusted = ["127.0.0.0/8", "10.0.0.0/8"];
+
+        assert_eq!(
+            resolve(Some("127.0.0.1:1234"), &["203.0.113.7"], &trusted),
+            ip("203.0.113.7")
+        );
+        // The client forged the first address, the proxies appended the rest
+        assert_eq!(
+            resolve(
+                Some("127.0.0.1:1234"),
+                &["198.51.100.1, 203.0.113.7, 10.0.0.2"],
+                &trusted
+            ),
+            ip("203.0.113.7")
+        );
+        // Proxies may send a header each
+        assert_eq!(
+            resolve(
+                Some("127.0.0.1:1234"),
+                &["198.51.100.1", "203.0.113.7", "10.0.0.2"],
+                &trusted
+            ),
+            ip("203.0.113.7")
+        );
+        // An unparsable address is untrusted, so nothing left of it is believed
+        assert_eq!(
+            resolve(Some("127.0.0.1:1234"), &["198.51.100.1, garbage"], &trusted),
+            None
+        );
+        // When every address is trusted the leftmost one is the client
+        assert_eq!(
+            resolve(Some("127.0.0.1:1234"), &["10.0.0.3, 10.0.0.2"], &trusted),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn converts_ipv4_mapped_addresses() {
+        let trusted = ["127.0.0.0/8"];
+
+        assert_eq!(
+            resolve(Some("[::ffff:192.0.2.1]:1234"), &["203.0.113.7"], &trusted),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            resolve(
+                Some("[::ffff:127.0.0.1]:1234"),
+                &["::ffff:203.0.113.7, ::ffff:127.0.0.2"],
+                &trusted
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            resolve(Some("[::1]:1234"), &["203.0.113.7"], &["::1/128"]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn trusts_unix_sockets() {
+        assert_eq!(
+            resolve(None, &["198.51.100.1, 203.0.113.7"], &[]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(resolve(None, &[], &[]), None);
+    }
+
+    #[test]
+    fn falls_back_to_trusted_peer_without_header() {
+        assert_eq!(
+            resolve(Some("127.0.0.1:1234"), &[], &["127.0.0.0/8"]),
+            ip("127.0.0.1")
+        );
+    }
+}
@@ -1,55 +1,108 @@
+use std::time::Duration;
+
 use axum::{
     Json, Router,
-    body::Body,
+    body::{Body, Bytes},
     extract::State,
     http::{Response, StatusCode},
+    middleware,
     response::IntoResponse,
-    routing::post,
+    routing::{get, post},
 };
+use rand::Rng;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use crate::{
     bot::{
         api::{
             entities::{
-                CallbackData, CallbackQuery, ChatType, InlineKeyboardButton, InlineKeyboardMarkup,
-                Message, UpdateMessage, WebhookResponse,
+                CallbackQuery, ChatType, InlineKeyboardButton, InlineKeyboardMarkup, Message,
+                UpdateMessage, WebhookResponse,
             },
-            headers::ApiSecretToken,
+            headers::{ApiSecretToken, ClientIp},
         },
-        entities::{SendAnimationPayload, SendPhotoPayload, SendStickerPayload},
+        callback_data::{CallbackData, InvalidCallbackData},
+        client, publisher,
     },
+    chats::{ChatInfo, Mixing},
     log::{FutureExt, debug, error, info, logger, o},
+    metrics::{
+        DUPLICATE_UPDATES, REFUSED_MESSAGES, UPDATE_PARSE_FAILURES, UPDATES_RECEIVED,
+        WEBHOOK_HANDLING_SECONDS,
+    },
+    outbox::{OutgoingContent, OutgoingMessage},
+    sanitize,
     state::AppState,
 };
 
+mod admin;
+mod allowlist;
+mod dialogue;
 pub mod entities;
+mod group_admin;
 mod headers;
+mod posts;
+
+/// Largest file bots can download.
+const MAX_DOCUMENT_SIZE: i64 = 20 * 1024 * 1024;
+/// How long the link to the bot stays in the chat after /send of a user the bot can't write to.
+const LINK_REPLY_LIFETIME: Duration = Duration::from_secs(60);
 
 pub fn make_router(state: AppState) -> Router {
+    let router = Router::new()
+        .route(&state.config().http.webhook_path(), post(update))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            allowlist::allow_listed_ips,
+        ));
+
+    let router = match state.config().http.admin {
+        Some(_) => router,
+        // Metrics tell when anonymous messages are sent, so they are only served to local clients
+        None => router
+            .merge(make_admin_router().route_layer(middleware::from_fn(allowlist::local_clients))),
+    };
+
+    router.with_state(state)
+}
+
+pub fn make_admin_router() -> Router<AppState> {
     Router::new()
-        .route("/update", post(update))
-        .with_state(state)
+        .route("/metrics", get(admin::metrics))
+        .route("/healthz", get(admin::healthz))
+        .route("/readyz", get(admin::readyz))
 }
 
 pub async fn update(
     State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
     api_token: Option<ApiSecretToken>,
-    Json(request): Json<serde_json::Value>,
+    body: Bytes,
 ) -> Response<Body> {
-    if state
-        .config()
-        .auth
-        .api_token
-        .as_deref()
-        .is_some_and(|expected_token| Some(expected_token) != api_token.as_deref())
+    let _timer = WEBHOOK_HANDLING_SECONDS.start_timer();
+
     {
-        error!("Incorrect request token");
-        return StatusCode::BAD_REQUEST.into_response();
+        let api_tokens = state.api_tokens().read().await;
+        match api_token {
+            _ if api_tokens.is_empty() => {}
+            None => {
+                error!("Request without token");
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Some(token) if !api_tokens.accepts(&token) => {
+                error!("Incorrect request token");
+                return StatusCode::FORBIDDEN.into_response();
+            }
+            Some(_) => {}
+        }
     }
 
-    match handle_request(&state, request)
-        .with_logger(logger().new(o!("uuid" => Uuid::new_v4().to_string())))
+    match handle_request(&state, &body)
+        .with_logger(logger().new(o!(
+            "uuid" => Uuid::new_v4().to_string(),
+            "client_ip" => client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string()),
+        )))
         .await
     {
         Ok(Some(body)) => (StatusCode::OK, Json(body)).into_response(),
@@ -61,61 +114,301 @@ pub async fn update(
     }
 }
 
-async fn handle_request(
-    state: &AppState,
-    request: serde_json::Value,
-) -> anyhow::Result<Option<WebhookResponse>> {
-    debug!("Got new request: {request:#?}");
+async fn handle_request(state: &AppState, body: &[u8]) -> anyhow::Result<Option<WebhookResponse>> {
+    if state.config().log.level.is_at_least(slog::Level::Debug) {
+        debug!(
+            "Got new request: {}",
+            std::str::from_utf8(body).unwrap_or("<invalid utf-8>")
+        );
+    }
 
-    let parsed_request = match serde_json::from_value::<UpdateMessage>(request) {
+    let parsed_request = match serde_json::from_slice::<UpdateMessage>(body) {
         Ok(req) => req,
         Err(err) => {
+            UPDATE_PARSE_FAILURES.inc();
             info!("Failed to parse message: {err}. Skipping");
             return Ok(None);
         }
     };
 
-    if let Some(message) = parsed_request.message.as_ref() {
-        handle_message(state, message).await?;
+    let update_type = match &parsed_request {
+        UpdateMessage {
+            message: Some(_), ..
+        } => "message",
+        UpdateMessage {
+            edited_message: Some(_),
+            ..
+        } => "edited_message",
+        UpdateMessage {
+            callback_query: Some(_),
+            ..
+        } => "callback_query",
+        _ => "other",
+    };
+    UPDATES_RECEIVED.with_label_values(&[update_type]).inc();
+
+    let update_id = parsed_request.update_id;
+    if !state.processed_updates().begin(update_id).await {
+        DUPLICATE_UPDATES.inc();
+        info!("Update {update_id} is already handled. Skipping");
+        return Ok(None);
+    }
+
+    let reply = state.pending_replies().register(update_id);
+
+    // Telegram gets an error and redelivers the update when it can't be queued
+    let sender_id = parsed_request.sender_id();
+    if let Err(err) = state.update_queue().push(update_id, sender_id, body).await {
+        state.pending_replies().remove(update_id);
+        state.processed_updates().finish(update_id, false).await;
+        return Err(err);
+    }
+
+    Ok(wait_for_reply(state, update_id, reply).await)
+}
+
+/// Waits a little for the worker to handle the update, so its reply goes back in the response.
+async fn wait_for_reply(
+    state: &AppState,
+    update_id: i64,
+    mut reply: oneshot::Receiver<WebhookResponse>,
+) -> Option<WebhookResponse> {
+    let reply_wait = Duration::from_millis(state.config().queue.reply_wait_ms);
+    if let Ok(Ok(reply)) = tokio::time::timeout(reply_wait, &mut reply).await {
+        return Some(reply);
+    }
+
+    // The worker sends the reply itself from now on, unless it has just handed it over
+    state.pending_replies().remove(update_id);
+    reply.try_recv().ok()
+}
+
+/// Handles the update and returns its primary reply, which is sent in the webhook response when
+/// possible. Other replies are sent with the client right away.
+pub async fn handle_update(
+    state: &AppState,
+    update: &UpdateMessage<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if let Some(message) = update.message.as_ref() {
+        return handle_message(state, message).await;
     };
-    if let Some(callback_query) = parsed_request.callback_query.as_ref() {
-        handle_button_click(state, callback_query).await?;
+    if let Some(message) = update.edited_message.as_ref() {
+        return posts::handle_edited_message(state, message).await;
+    }
+    if let Some(callback_query) = update.callback_query.as_ref() {
+        return handle_button_click(state, callback_query).await;
     }
 
     Ok(None)
 }
 
-async fn handle_message(state: &AppState, message: &Message) -> anyhow::Result<()> {
-    let is_send_cmd = message
-        .text
-        .as_deref()
-        .is_some_and(|text| text.starts_with("/send"));
+async fn handle_message(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    match message.text.as_deref().and_then(parse_command) {
+        Some(("/send", _)) => handle_send_command(state, message).await,
+        Some(("/start", payload)) => handle_start_command(state, message, payload).await,
+        Some(("/delete", _)) => posts::handle_delete_command(state, message).await,
+        Some(("/confirm", _)) => dialogue::handle_confirm_command(state, message).await,
+        Some(("/cancel", _)) => dialogue::handle_cancel_command(state, message).await,
+        Some(("/anonlink", _)) => group_admin::handle_link_command(state, message).await,
+        Some(("/anonsilent", argument)) => {
+            group_admin::handle_silent_command(state, message, argument).await
+        }
+        Some(("/anonmin", argument)) => {
+            group_admin::handle_min_senders_command(state, message, argument).await
+        }
+        Some(("/anonmix", argument)) => {
+            group_admin::handle_mixing_command(state, message, argument).await
+        }
+        _ => handle_text_message(state, message).await,
+    }
+}
 
-    if is_send_cmd {
-        handle_send_command(state, message).await
-    } else {
-        handle_text_message(state, message).await
+/// Splits `/command@bot_name payload` into the command and its payload.
+fn parse_command(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with('/') {
+        return None;
     }
+
+    let (command, payload) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let command = command
+        .split_once('@')
+        .map_or(command, |(command, _)| command);
+
+    Some((command, payload.trim()))
 }
 
-async fn handle_send_command(state: &AppState, message: &Message) -> anyhow::Result<()> {
+/// Hides the /send command from the chat and confirms the registration in private, so nobody
+/// learns who can post anonymously. Telegram doesn't let the bot write first to users who never
+/// started it, they get a link to the bot in the chat instead, which is deleted shortly.
+async fn register_silently(
+    state: &AppState,
+    message: &Message<'_>,
+    user_id: i64,
+    added: bool,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if let Err(err) = state
+        .tg_client()
+        .delete_message(message.chat.id, message.message_id)
+        .await
+    {
+        error!(
+            "Failed to delete /send in chat {}: {err:#}",
+            message.chat.id
+        );
+    }
+
+    let title = message.chat.title.as_deref().unwrap_or("без названия");
+    let text = match added {
+        true => format!("Теперь ты можешь отправлять анонимные сообщения в чат \"{title}\""),
+        false => format!("Ты уже можешь отправлять анонимные сообщения в чат \"{title}\""),
+    };
+
+    // Private chats share ids with their users
+    let err = match state.tg_client().send_text(user_id, &text).await {
+        Ok(_) => return Ok(None),
+        Err(err) if client::is_forbidden_error(&err) => err,
+        Err(err) => return Err(err),
+    };
+    debug!("Can't confirm /send in private, sending a link to the bot: {err:#}");
+
+    let bot_username = state.tg_client().bot_username().await?;
+    let link_payload = state.chat_link_signer().encode(message.chat.id);
+    let sent = state
+        .tg_client()
+        .send_text(
+            message.chat.id,
+            &format!(
+                "Чтобы отправлять анонимные сообщения, начни диалог с ботом: https://t.me/{bot_username}?start={link_payload}"
+            ),
+        )
+        .await?;
+
+    let chat_id = message.chat.id;
+    let tasks = state.tasks();
+    let state = state.clone();
+    tasks.spawn(async move {
+        // The link is deleted right away on shutdown, nothing would delete it after a restart
+        tokio::select! {
+            _ = tokio::time::sleep(LINK_REPLY_LIFETIME) => {},
+            _ = state.cancellation_token().cancelled() => {},
+        }
+        if let Err(err) = state
+            .tg_client()
+            .delete_message(chat_id, sent.message_id)
+            .await
+        {
+            error!("Failed to delete link to the bot in chat {chat_id}: {err:#}");
+        }
+    });
+
+    Ok(None)
+}
+
+/// Handles `/start c_<token>` links from /anonlink: registers the user in the chat, selects it
+/// and asks for the message in one go. Plain /start shows the chat selection.
+async fn handle_start_command(
+    state: &AppState,
+    message: &Message<'_>,
+    link_payload: &str,
+) -> anyhow::Result<Option<WebhookResponse>> {
     let user = match message.from.as_ref() {
         Some(user) if !user.is_bot => user,
-        _ => return Ok(()),
+        _ => return Ok(None),
     };
+    if !matches!(message.chat.chat_type, ChatType::Private) {
+        return Ok(None);
+    }
+    if link_payload.is_empty() {
+        return dialogue::show_chat_selection(state, user.id)
+            .await
+            .map(Some);
+    }
 
-    match message.chat.chat_type {
-        ChatType::Private => {
-            let payload = make_bot_chat_selection_message(state, message.chat.id, user.id).await;
+    let invalid_link = || {
+        let payload = make_bot_text_message(
+            message.chat.id,
+            "Ссылка недействительна. Попроси администратора чата прислать новую командой /anonlink",
+        );
+
+        Ok(Some(WebhookResponse::new("sendMessage", payload)))
+    };
+    let Some(target_chat_id) = state.chat_link_signer().decode(link_payload) else {
+        info!("Got invalid chat link from user {}", user.id);
+        return invalid_link();
+    };
+    if state.chats().get_chat(target_chat_id).await.is_none() {
+        info!(
+            "Got link to unknown chat {target_chat_id} from user {}",
+            user.id
+        );
+        return invalid_link();
+    }
 
-            state.tg_client().send_message(&payload).await;
+    let is_member = match state
+        .tg_client()
+        .get_chat_member(target_chat_id, user.id)
+        .await
+    {
+        Ok(member) => member.is_member(),
+        Err(err) => {
+            // Telegram doesn't tell anything about chats the bot has left
+            info!("Failed to check membership in chat {target_chat_id}: {err:#}");
+            return invalid_link();
         }
+    };
+    if !is_member {
+        info!(
+            "User {} followed link to chat {target_chat_id} without being its member",
+            user.id
+        );
+        let payload = make_bot_text_message(
+            message.chat.id,
+            "Ты не состоишь в этом чате, поэтому не можешь отправлять в него сообщения",
+        );
+
+        return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+    }
+
+    if state.chats().add_member(user.id, target_chat_id).await {
+        state.save_chats().await?;
+    }
+
+    dialogue::select_chat(state, user.id, target_chat_id)
+        .await
+        .map(Some)
+}
+
+async fn handle_send_command(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let user = match message.from.as_ref() {
+        Some(user) if !user.is_bot => user,
+        _ => return Ok(None),
+    };
+
+    match message.chat.chat_type {
+        ChatType::Private => dialogue::show_chat_selection(state, user.id)
+            .await
+            .map(Some),
         _ => {
             let added = state.chats().add_user_chat(user.id, &message.chat).await;
             if added {
                 state.save_chats().await?;
             }
 
+            let silent = state
+                .chats()
+                .get_chat(message.chat.id)
+                .await
+                .is_some_and(|chat| chat.settings.silent);
+            if silent {
+                return register_silently(state, message, user.id, added).await;
+            }
+
             let tagged_username = user
                 .username
                 .as_deref()
@@ -132,134 +425,235 @@ async fn handle_send_command(state: &AppState, message: &Message) -> anyhow::Res
             };
 
             let payload = make_bot_text_message(message.chat.id, &response_message_text);
-            state.tg_client().send_message(&payload).await;
+
+            Ok(Some(WebhookResponse::new("sendMessage", payload)))
         }
     }
-
-    Ok(())
 }
 
-async fn handle_text_message(state: &AppState, message: &Message) -> anyhow::Result<()> {
+async fn handle_text_message(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
     if !matches!(message.chat.chat_type, ChatType::Private) {
-        return Ok(());
+        return Ok(None);
     }
     let Some(user) = message.from.as_ref() else {
-        return Ok(());
+        return Ok(None);
     };
 
-    let target_chat_id = { state.user_chats().read().await.get(&user.id).copied() };
+    dialogue::handle_private_message(state, message, user.id).await
+}
 
-    match target_chat_id {
-        Some(chat_id) => resend_message_anonimously(state, message, chat_id).await,
-        None => {
-            let payload = make_bot_chat_selection_message(state, message.chat.id, user.id).await;
+/// Mixing of the chat, or the refusal for the author when messages can't be sent there.
+pub(super) async fn check_target_chat(
+    state: &AppState,
+    author_chat_id: i64,
+    target_chat_id: i64,
+) -> Result<Mixing, WebhookResponse> {
+    let text = match state.chats().get_chat(target_chat_id).await {
+        Some(chat) if chat.is_anonymous() => return Ok(chat.settings.mixing),
+        Some(chat) => format!(
+            "Сообщение не отправлено: в чате \"{}\" анонимно пишут только {} из {} необходимых участников, и все догадаются, кто его написал. Позови ещё участников отправить /send в чате",
+            chat.title.as_deref().unwrap_or("без названия"),
+            chat.members.len(),
+            chat.settings.min_senders,
+        ),
+        None => "Сообщение не отправлено: этот чат больше недоступен. Отправь /send, чтобы выбрать другой".to_string(),
+    };
 
-            state.tg_client().send_message(&payload).await;
+    REFUSED_MESSAGES.inc();
+    info!("Refused message to chat {target_chat_id} with too few senders");
+    let payload = make_bot_text_message(author_chat_id, &text);
 
-            Ok(())
-        }
-    }
+    Err(WebhookResponse::new("sendMessage", payload))
 }
 
-async fn resend_message_anonimously(
+/// Publishes the message right away, or holds it back in the outbox when the chat mixes
+/// messages.
+pub(super) async fn post_anonymously(
     state: &AppState,
-    message: &Message,
+    author_chat_id: i64,
+    source_message_id: i32,
     target_chat_id: i64,
-) -> anyhow::Result<()> {
-    if let Some(text) = message.text.as_deref() {
-        let payload = make_bot_text_message(target_chat_id, text);
-        state.tg_client().send_message(&payload).await;
-    }
+    mixing: Mixing,
+    contents: Vec<OutgoingContent>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let Some(release_at) = mixing.release_at(chrono::Utc::now().timestamp()) else {
+        let published = publisher::publish(
+            state,
+            author_chat_id,
+            source_message_id,
+            target_chat_id,
+            &contents,
+        )
+        .await;
+        if let Err(err) = published {
+            error!("Failed to publish message to chat {target_chat_id}: {err:#}");
+            let payload =
+                make_bot_text_message(author_chat_id, publisher::publish_failed_text(&err));
 
-    if let Some(photo_sizes) = message.photo.as_ref().filter(|v| !v.is_empty()) {
-        let file_id = &*photo_sizes[0].file_id;
+            return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+        }
+        return Ok(None);
+    };
 
-        state
-            .tg_client()
-            .send_photo(SendPhotoPayload {
-                chat_id: target_chat_id,
-                photo: file_id,
-                caption: message.caption.as_deref(),
-            })
-            .await;
+    let id = rand::rng().random();
+    state
+        .outbox()
+        .push(OutgoingMessage {
+            id,
+            chat_id: target_chat_id,
+            author_chat_id,
+            source_message_id,
+            release_at,
+            contents,
+            attempts: 0,
+            publishing: false,
+        })
+        .await;
+    state.save_outbox().await?;
+
+    let payload = make_bot_text_message(
+        author_chat_id,
+        "Сообщение будет опубликовано чуть позже, чтобы по времени нельзя было догадаться, кто его написал. Я напишу, когда оно появится в чате",
+    );
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Explains why a document can't be sent anonymously, if it can't.
+pub(super) fn check_document(message: &Message<'_>) -> Option<&'static str> {
+    if message.animation.is_some() {
+        return None;
     }
+    let document = message.document.as_ref()?;
 
-    if let Some(animation) = message.animation.as_ref() {
-        state
-            .tg_client()
-            .send_animation(SendAnimationPayload {
-                chat_id: target_chat_id,
-                animation: animation.file_id.as_ref(),
-                duration: Some(animation.duration),
-                width: Some(animation.width),
-                height: Some(animation.height),
-                caption: message.caption.as_deref(),
-            })
-            .await;
+    if document
+        .file_size
+        .is_some_and(|size| size > MAX_DOCUMENT_SIZE)
+    {
+        return Some("Файл не отправлен: боты могут скачивать файлы только до 20 МБ");
     }
-    if let Some(sticker) = message.sticker.as_ref() {
-        state
-            .tg_client()
-            .send_sticker(SendStickerPayload {
-                chat_id: target_chat_id,
-                sticker: &sticker.file_id,
-            })
-            .await;
+    if document
+        .file_name
+        .as_deref()
+        .and_then(sanitize::supported_extension)
+        .is_none()
+    {
+        return Some(
+            "Файл не отправлен: из файлов такого типа я не умею убирать данные об авторе. Можно отправить фото и картинки (JPEG, PNG, WebP, HEIC), PDF и документы Office и OpenDocument",
+        );
     }
 
-    Ok(())
+    None
 }
 
-async fn handle_button_click(state: &AppState, query: &CallbackQuery) -> anyhow::Result<()> {
+async fn handle_button_click(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
     if query.from.is_bot {
-        return Ok(());
+        return Ok(None);
     }
 
-    match query.data.as_ref() {
-        Some(CallbackData::ActionSend) => {
-            handle_chat_select_button_clicked(state, query).await?;
+    let Some(data) = query.data.as_deref() else {
+        return Ok(None);
+    };
+    let data = match state.callback_signer().decode(data, query.from.id) {
+        Ok(data) => data,
+        Err(InvalidCallbackData::Unsigned) => {
+            info!("Got button of an old version from user {}", query.from.id);
+
+            return handle_legacy_button_clicked(state, query).await;
         }
-        Some(CallbackData::SendTo(target_chat_id)) => {
-            handle_chat_button_clicked(state, query, *target_chat_id).await?;
+        Err(InvalidCallbackData::Invalid) => {
+            info!("Got stale or forged button from user {}", query.from.id);
+
+            return Ok(Some(make_answer_callback_query_alert(
+                &query.id,
+                "Эта кнопка устарела. Отправь /send, чтобы выбрать чат заново",
+            )));
         }
-        None => {}
-    }
+    };
 
-    Ok(())
+    match data {
+        CallbackData::ActionSend => handle_chat_select_button_clicked(state, query).await,
+        CallbackData::SendTo(target_chat_id) => {
+            handle_chat_button_clicked(state, query, target_chat_id).await
+        }
+        CallbackData::DeletePost(source_message_id) => {
+            posts::handle_delete_button_clicked(state, query, source_message_id).await
+        }
+        CallbackData::ConfirmDraft(source_message_id, chat_id) => {
+            dialogue::handle_confirm_button_clicked(state, query, source_message_id, chat_id).await
+        }
+        CallbackData::ChangeDraftChat(source_message_id) => {
+            dialogue::handle_change_chat_button_clicked(state, query, source_message_id).await
+        }
+        CallbackData::CancelDraft(source_message_id) => {
+            dialogue::handle_cancel_button_clicked(state, query, source_message_id).await
+        }
+    }
 }
 
 async fn handle_chat_select_button_clicked(
     state: &AppState,
-    query: &CallbackQuery,
-) -> anyhow::Result<()> {
-    let Some(orig_message) = query.message.as_deref() else {
-        return Ok(());
-    };
+    query: &CallbackQuery<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if query.message.is_none() {
+        return Ok(Some(make_answer_callback_query(&query.id)));
+    }
 
-    let payload = make_bot_chat_selection_message(state, orig_message.chat.id, query.from.id).await;
+    let reply = dialogue::show_chat_selection(state, query.from.id).await?;
+    state.tg_client().send_reply(&reply).await;
 
-    state.tg_client().send_message(&payload).await;
-    state
-        .tg_client()
-        .answer_callback_query(&query.id, None)
-        .await;
+    Ok(Some(make_answer_callback_query(&query.id)))
+}
+
+/// Buttons of old versions can't be trusted, but the user doesn't have to look for /send either:
+/// they get a fresh keyboard right away.
+async fn handle_legacy_button_clicked(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let text = "Эта кнопка устарела, выбери чат заново";
+    if query.message.is_none() {
+        return Ok(Some(make_answer_callback_query_alert(&query.id, text)));
+    }
+
+    let reply = dialogue::show_chat_selection(state, query.from.id).await?;
+    state.tg_client().send_reply(&reply).await;
 
-    Ok(())
+    Ok(Some(WebhookResponse::new(
+        "answerCallbackQuery",
+        serde_json::json!({
+            "callback_query_id": query.id,
+            "text": text,
+        }),
+    )))
 }
 
 async fn handle_chat_button_clicked(
     state: &AppState,
-    query: &CallbackQuery,
+    query: &CallbackQuery<'_>,
     target_chat: i64,
-) -> anyhow::Result<()> {
-    {
-        state
-            .user_chats()
-            .write()
-            .await
-            .entry(query.from.id)
-            .and_modify(|chat| *chat = target_chat)
-            .or_insert(target_chat);
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let is_member = state
+        .chats()
+        .get_chat(target_chat)
+        .await
+        .is_some_and(|chat| chat.members.contains(&query.from.id));
+    if !is_member {
+        info!(
+            "User {} is not registered in chat {target_chat}",
+            query.from.id
+        );
+
+        return Ok(Some(make_answer_callback_query_alert(
+            &query.id,
+            "Ты не можешь отправлять сообщения в этот чат. Отправь /send в нём, чтобы получить доступ",
+        )));
     }
 
     state
@@ -267,17 +661,32 @@ async fn handle_chat_button_clicked(
         .answer_callback_query(&query.id, None)
         .await;
 
-    let Some(orig_message) = query.message.as_deref() else {
-        return Ok(());
-    };
+    dialogue::select_chat(state, query.from.id, target_chat)
+        .await
+        .map(Some)
+}
 
-    let payload = make_bot_text_message(orig_message.chat.id, "Напиши текст сообщения");
-    state.tg_client().send_message(&payload).await;
+pub(super) fn make_answer_callback_query(query_id: &str) -> WebhookResponse {
+    WebhookResponse::new(
+        "answerCallbackQuery",
+        serde_json::json!({
+            "callback_query_id": query_id,
+        }),
+    )
+}
 
-    Ok(())
+pub(super) fn make_answer_callback_query_alert(query_id: &str, text: &str) -> WebhookResponse {
+    WebhookResponse::new(
+        "answerCallbackQuery",
+        serde_json::json!({
+            "callback_query_id": query_id,
+            "text": text,
+            "show_alert": true,
+        }),
+    )
 }
 
-fn make_bot_text_message(chat_id: i64, text: &str) -> serde_json::Value {
+pub(super) fn make_bot_text_message(chat_id: i64, text: &str) -> serde_json::Value {
     serde_json::json!({
         "chat_id": chat_id,
         "text": text,
@@ -297,17 +706,17 @@ fn make_bot_text_message(chat_id: i64, text: &str) -> serde_json::Value {
 //     })
 // }
 
-async fn make_bot_chat_selection_message(
+pub(super) async fn make_bot_chat_selection_message(
     state: &AppState,
     user_chat_id: i64,
     user_id: i64,
-) -> serde_json::Value {
+) -> anyhow::Result<serde_json::Value> {
     let chats = state.chats().get_user_chats(user_id).await;
     if chats.is_empty() {
-        return make_no_chats_message(user_chat_id);
+        return Ok(make_no_chats_message(user_chat_id));
     }
     let message_text = {
-        let chosen_chat_id = { state.user_chats().read().await.get(&user_id).copied() };
+        let chosen_chat_id = state.dialogues().get(user_id).await.chat_id();
 
         let chosen_chat = match chosen_chat_id {
             Some(id) => state.chats().get_chat(id).await,
@@ -316,29 +725,47 @@ async fn make_bot_chat_selection_message(
 
         chosen_chat.as_ref().and_then(|chat| chat.title.as_deref()).map(|title| format!("Ты уже можешь отправлять сообщения в чат \"{title}\". Если хочешь отправить в другой, то выбери его ниже")).unwrap_or_else(|| "Выбери чат".to_string())
     };
+    let message_text = match chats.iter().all(ChatInfo::is_anonymous) {
+        true => message_text,
+        false => format!(
+            "{message_text}\n\n⚠️ — в чате пока слишком мало участников пишут анонимно, сообщения туда не отправляются"
+        ),
+    };
 
     let buttons = chats
         .iter()
-        .filter_map(|chat| match chat.title.as_deref() {
-            Some(title) => Some(vec![InlineKeyboardButton {
-                text: title.to_string(),
-                callback_data: CallbackData::SendTo(chat.id),
-            }]),
-            None => None,
+        .filter_map(|chat| {
+            chat.title.as_deref().map(|title| {
+                let text = match chat.is_anonymous() {
+                    true => title.to_string(),
+                    false => format!(
+                        "⚠️ {title} ({}/{})",
+                        chat.members.len(),
+                        chat.settings.min_senders
+                    ),
+                };
+
+                anyhow::Ok(vec![InlineKeyboardButton {
+                    text,
+                    callback_data: state
+                        .callback_signer()
+                        .encode(CallbackData::SendTo(chat.id), user_id)?,
+                }])
+            })
         })
-        .collect::<Vec<_>>();
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
     if buttons.is_empty() {
-        return make_no_chats_message(user_chat_id);
+        return Ok(make_no_chats_message(user_chat_id));
     }
 
-    serde_json::json!({
+    Ok(serde_json::json!({
         "chat_id": user_chat_id,
         "text": message_text,
         "reply_markup": InlineKeyboardMarkup {
             inline_keyboard: buttons,
         }
-    })
+    }))
 }
 
 fn make_no_chats_message(chat_id: i64) -> serde_json::Value {
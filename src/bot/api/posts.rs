@@ -0,0 +1,220 @@
+use crate::{
+    bot::api::{
+        dialogue,
+        entities::{CallbackQuery, ChatType, Message, WebhookResponse},
+        make_answer_callback_query_alert, make_bot_text_message,
+    },
+    log::{error, info},
+    outbox::{HeldBack, OutgoingContent},
+    posts::PartKind,
+    state::AppState,
+};
+
+const PUBLISHING_TEXT: &str =
+    "Сообщение как раз публикуется, поэтому не изменилось. Попробуй ещё раз через минуту";
+
+/// Deletes the anonymous post made from the message the command replies to.
+pub(super) async fn handle_delete_command(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if !matches!(message.chat.chat_type, ChatType::Private) {
+        return Ok(None);
+    }
+    let Some(user) = message.from.as_ref() else {
+        return Ok(None);
+    };
+
+    let source_message = message
+        .reply_to_message
+        .as_deref()
+        .filter(|source| source.from.as_ref().is_some_and(|from| from.id == user.id));
+    let text = match source_message {
+        Some(source) => delete_post(state, user.id, source.message_id).await?,
+        None => {
+            "Ответь командой /delete на своё сообщение, которое нужно удалить из чата".to_string()
+        }
+    };
+    let payload = make_bot_text_message(message.chat.id, &text);
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Handles the button on the confirmation of a held back message being published.
+pub(super) async fn handle_delete_button_clicked(
+    state: &AppState,
+    query: &CallbackQuery<'_>,
+    source_message_id: i32,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    let text = delete_post(state, query.from.id, source_message_id).await?;
+
+    Ok(Some(make_answer_callback_query_alert(&query.id, &text)))
+}
+
+/// Withdraws the author's message from the outbox, or deletes it from the chat while the edit
+/// window lasts. Returns the outcome for the author.
+async fn delete_post(
+    state: &AppState,
+    author_id: i64,
+    source_message_id: i32,
+) -> anyhow::Result<String> {
+    // Private chats share ids with their users
+    match state.outbox().withdraw(author_id, source_message_id).await {
+        HeldBack::Waiting => {
+            state.save_outbox().await?;
+            info!("Withdrew held back message of user {author_id}");
+
+            return Ok("Сообщение удалено, оно не будет опубликовано".to_string());
+        }
+        HeldBack::Publishing => return Ok(PUBLISHING_TEXT.to_string()),
+        HeldBack::No => {}
+    }
+
+    let post = state
+        .posts()
+        .find(author_id, source_message_id, state.edit_window_start())
+        .await;
+    let Some(post) = post else {
+        return Ok(format!(
+            "Сообщение не удалено: удалять можно только свои анонимные сообщения и только в течение {} после публикации",
+            describe_edit_window(state.config().posts.edit_window_secs)
+        ));
+    };
+
+    let mut deleted = true;
+    for part in &post.parts {
+        if let Err(err) = state
+            .tg_client()
+            .delete_message(post.chat_id, part.message_id)
+            .await
+        {
+            error!("Failed to delete post from chat {}: {err:#}", post.chat_id);
+            deleted = false;
+        }
+    }
+    state.posts().remove(author_id, source_message_id).await;
+    state.save_posts().await?;
+    info!(
+        "Deleted post of user {author_id} from chat {}",
+        post.chat_id
+    );
+
+    Ok(match deleted {
+        true => "Сообщение удалено из чата".to_string(),
+        false => "Сообщение удалено не полностью: возможно, его уже удалили администраторы чата"
+            .to_string(),
+    })
+}
+
+/// Carries the author's edit over to the draft, the held back message or the published post.
+pub(super) async fn handle_edited_message(
+    state: &AppState,
+    message: &Message<'_>,
+) -> anyhow::Result<Option<WebhookResponse>> {
+    if !matches!(message.chat.chat_type, ChatType::Private) {
+        return Ok(None);
+    }
+
+    let contents = OutgoingContent::from_message(message);
+    if contents.is_empty() {
+        return Ok(None);
+    }
+    if dialogue::edit_draft(state, message.chat.id, message.message_id, contents.clone()).await? {
+        return Ok(None);
+    }
+    match state
+        .outbox()
+        .edit(message.chat.id, message.message_id, contents)
+        .await
+    {
+        HeldBack::Waiting => {
+            state.save_outbox().await?;
+
+            return Ok(None);
+        }
+        HeldBack::Publishing => {
+            let payload = make_bot_text_message(message.chat.id, PUBLISHING_TEXT);
+
+            return Ok(Some(WebhookResponse::new("sendMessage", payload)));
+        }
+        HeldBack::No => {}
+    }
+
+    // Edits of anything but anonymous messages are of no interest
+    let Some(post) = state
+        .posts()
+        .find(
+            message.chat.id,
+            message.message_id,
+            state.edit_window_start(),
+        )
+        .await
+    else {
+        return Ok(None);
+    };
+
+    let mut edited = true;
+    for part in &post.parts {
+        let result = match (part.kind, message.text.as_deref()) {
+            (PartKind::Text, Some(text)) => {
+                state
+                    .tg_client()
+                    .edit_message_text(post.chat_id, part.message_id, text)
+                    .await
+            }
+            (PartKind::Media, _) => {
+                state
+                    .tg_client()
+                    .edit_message_caption(post.chat_id, part.message_id, message.caption.as_deref())
+                    .await
+            }
+            (PartKind::Text, None) | (PartKind::Other, _) => continue,
+        };
+        if let Err(err) = result {
+            error!("Failed to edit post in chat {}: {err:#}", post.chat_id);
+            edited = false;
+        }
+    }
+    if edited {
+        return Ok(None);
+    }
+
+    let payload = make_bot_text_message(
+        message.chat.id,
+        "Не получилось изменить сообщение в чате. Если нужно, удали его командой /delete и отправь заново",
+    );
+
+    Ok(Some(WebhookResponse::new("sendMessage", payload)))
+}
+
+/// Rounds down to minutes, as the window is usually set in whole hours or minutes.
+fn describe_edit_window(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{secs} сек."),
+        (0, minutes) => format!("{minutes} мин."),
+        (hours, 0) => format!("{hours} ч"),
+        (hours, minutes) => format!("{hours} ч {minutes} мин."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_edit_windows_under_an_hour() {
+        assert_eq!(describe_edit_window(30), "30 сек.");
+        assert_eq!(describe_edit_window(60), "1 мин.");
+        assert_eq!(describe_edit_window(15 * 60 + 30), "15 мин.");
+        assert_eq!(describe_edit_window(3599), "59 мин.");
+    }
+
+    #[test]
+    fn describes_edit_windows_in_hours() {
+        assert_eq!(describe_edit_window(3600), "1 ч");
+        assert_eq!(describe_edit_window(48 * 3600), "48 ч");
+        assert_eq!(describe_edit_window(90 * 60), "1 ч 30 мин.");
+    }
+}
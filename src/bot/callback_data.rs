@@ -0,0 +1,497 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+use crate::{
+    bot::signing::{Signer, TAG_LENGTH},
+    config::AuthConfig,
+};
+
+/// Version of the binary format, the first byte of every button.
+const VERSION: u8 = 1;
+/// Telegram's limit for `callback_data`.
+const MAX_ENCODED_LENGTH: usize = 64;
+const KEY_CONTEXT: &[u8] = b"anon callback data";
+
+// Registry of button actions. Codes are stored in buttons users already have, so a code must
+// never be reused for another action or change its params.
+const ACTION_SEND: u8 = 1;
+const ACTION_SEND_TO: u8 = 2;
+const ACTION_DELETE_POST: u8 = 3;
+const ACTION_CONFIRM_DRAFT: u8 = 4;
+const ACTION_CHANGE_DRAFT_CHAT: u8 = 5;
+const ACTION_CANCEL_DRAFT: u8 = 6;
+
+/// Separates payload and tag in the text format of the first signed buttons, e.g.
+/// `s-1001234567890.3q2-7wAAAAAAAAAA`. Base64 never contains it.
+const TEXT_FORMAT_TAG_SEPARATOR: char = '.';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackData {
+    ActionSend,
+    SendTo(i64),
+    /// Deletes the post made from the author's message with this id
+    DeletePost(i32),
+    /// Sends the draft made from the message with this id to the chat it was shown for
+    ConfirmDraft(i32, i64),
+    ChangeDraftChat(i32),
+    CancelDraft(i32),
+}
+
+impl CallbackData {
+    fn to_parts(self) -> (u8, Vec<i64>) {
+        match self {
+            Self::ActionSend => (ACTION_SEND, vec![]),
+            Self::SendTo(chat_id) => (ACTION_SEND_TO, vec![chat_id]),
+            Self::DeletePost(message_id) => (ACTION_DELETE_POST, vec![message_id.into()]),
+            Self::ConfirmDraft(message_id, chat_id) => {
+                (ACTION_CONFIRM_DRAFT, vec![message_id.into(), chat_id])
+            }
+            Self::ChangeDraftChat(message_id) => {
+                (ACTION_CHANGE_DRAFT_CHAT, vec![message_id.into()])
+            }
+            Self::CancelDraft(message_id) => (ACTION_CANCEL_DRAFT, vec![message_id.into()]),
+        }
+    }
+
+    fn from_parts(action: u8, params: &[i64]) -> Option<Self> {
+        match (action, params) {
+            (ACTION_SEND, []) => Some(Self::ActionSend),
+            (ACTION_SEND_TO, &[chat_id]) => Some(Self::SendTo(chat_id)),
+            (ACTION_DELETE_POST, &[message_id]) => {
+                i32::try_from(message_id).ok().map(Self::DeletePost)
+            }
+            (ACTION_CONFIRM_DRAFT, &[message_id, chat_id]) => i32::try_from(message_id)
+                .ok()
+                .map(|message_id| Self::ConfirmDraft(message_id, chat_id)),
+            (ACTION_CHANGE_DRAFT_CHAT, &[message_id]) => {
+                i32::try_from(message_id).ok().map(Self::ChangeDraftChat)
+            }
+            (ACTION_CANCEL_DRAFT, &[message_id]) => {
+                i32::try_from(message_id).ok().map(Self::CancelDraft)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidCallbackData {
+    /// Made by an old version of the bot which didn't sign buttons.
+    Unsigned,
+    /// Forged, made with another key or for another user, or of an unknown version.
+    Invalid,
+}
+
+/// Signs buttons so a modified client can't make up its own `callback_data`. Signatures are
+/// bound to the user the button was made for, so buttons can't be passed around either.
+///
+/// Buttons are base64 of `[version][action][params...][tag]`, params being zigzag varints.
+pub struct CallbackSigner {
+    signer: Signer,
+}
+
+impl CallbackSigner {
+    pub fn new(auth: &AuthConfig) -> Self {
+        Self {
+            signer: Signer::new(auth, KEY_CONTEXT),
+        }
+    }
+
+    /// Fails when the data doesn't fit into a button, as telegram would refuse the whole message.
+    pub fn encode(&self, data: CallbackData, user_id: i64) -> anyhow::Result<String> {
+        let (action, params) = data.to_parts();
+
+        let mut bytes = vec![VERSION, action];
+        for param in params {
+            write_varint(&mut bytes, param);
+        }
+        let tag = self.tag(&bytes, user_id);
+        bytes.extend_from_slice(&tag);
+
+        let encoded = URL_SAFE_NO_PAD.encode(bytes);
+        anyhow::ensure!(
+            encoded.len() <= MAX_ENCODED_LENGTH,
+            "Callback data {data:?} doesn't fit into a button"
+        );
+
+        Ok(encoded)
+    }
+
+    pub fn decode(&self, data: &str, user_id: i64) -> Result<CallbackData, InvalidCallbackData> {
+        // The very first buttons were plain JSON
+        if data.starts_with(['{', '"']) {
+            return Err(InvalidCallbackData::Unsigned);
+        }
+        if data.contains(TEXT_FORMAT_TAG_SEPARATOR) {
+            return self.decode_text_format(data, user_id);
+        }
+
+        let bytes = URL_SAFE_NO_PAD
+            .decode(data)
+            .map_err(|_| InvalidCallbackData::Invalid)?;
+        let body_length = bytes
+            .len()
+            .checked_sub(TAG_LENGTH)
+            .ok_or(InvalidCallbackData::Invalid)?;
+        let (body, tag) = bytes.split_at(body_length);
+        self.verify(body, tag, user_id)?;
+
+        let [VERSION, action, params @ ..] = body else {
+            return Err(InvalidCallbackData::Invalid);
+        };
+        let mut params = params;
+        let mut values = vec![];
+        while !params.is_empty() {
+            values.push(read_varint(&mut params).ok_or(InvalidCallbackData::Invalid)?);
+        }
+
+        CallbackData::from_parts(*action, &values).ok_or(InvalidCallbackData::Invalid)
+    }
+
+    /// Decodes buttons of the first signed format, `<payload>.<tag>`.
+    fn decode_text_format(
+        &self,
+        data: &str,
+        user_id: i64,
+    ) -> Result<CallbackData, InvalidCallbackData> {
+        let (payload, tag) = data
+            .rsplit_once(TEXT_FORMAT_TAG_SEPARATOR)
+            .ok_or(InvalidCallbackData::Invalid)?;
+        let tag = URL_SAFE_NO_PAD
+            .decode(tag)
+            .map_err(|_| InvalidCallbackData::Invalid)?;
+        self.verify(payload.as_bytes(), &tag, user_id)?;
+
+        match payload.split_at_checked(1) {
+            Some(("a", "")) => Ok(CallbackData::ActionSend),
+            Some(("s", chat_id)) => chat_id
+                .parse()
+                .map(CallbackData::SendTo)
+                .map_err(|_| InvalidCallbackData::Invalid),
+            _ => Err(InvalidCallbackData::Invalid),
+        }
+    }
+
+    fn tag(&self, body: &[u8], user_id: i64) -> [u8; TAG_LENGTH] {
+        self.signer.tag(&[body, &user_id.to_be_bytes()])
+    }
+
+    fn verify(&self, body: &[u8], tag: &[u8], user_id: i64) -> Result<(), InvalidCallbackData> {
+        match self.signer.verify(&[body, &user_id.to_be_bytes()], tag) {
+            true => Ok(()),
+            false => Err(InvalidCallbackData::Invalid),
+        }
+    }
+}
+
+/// Writes a zigzag LEB128 varint, so small negative numbers stay short too.
+pub(super) fn write_varint(out: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+pub(super) fn read_varint(input: &mut &[u8]) -> Option<i64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(((value >> 1) as i64) ^ -((value & 1) as i64));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const USER_ID: i64 = 123_456_789;
+
+    fn make_signer(bot_token: &str) -> CallbackSigner {
+        CallbackSigner::new(&AuthConfig {
+            bot_token: serde_json::from_value(serde_json::json!(bot_token)).unwrap(),
+            api_token: None,
+            api_tokens_storage: None,
+            allow_unauthenticated: false,
+        })
+    }
+
+    fn all_variants() -> Vec<CallbackData> {
+        let chat_ids = [0, -1, 1, -1_001_234_567_890, i64::MIN, i64::MAX];
+        let message_ids = [0, -1, 1, i32::MIN, i32::MAX];
+
+        let mut variants = vec![CallbackData::ActionSend];
+        variants.extend(chat_ids.map(CallbackData::SendTo));
+        for message_id in message_ids {
+            variants.push(CallbackData::DeletePost(message_id));
+            variants.push(CallbackData::ChangeDraftChat(message_id));
+            variants.push(CallbackData::CancelDraft(message_id));
+            variants
+                .extend(chat_ids.map(|chat_id| CallbackData::ConfirmDraft(message_id, chat_id)));
+        }
+
+        variants
+    }
+
+    /// Signs a body made by hand, as a client with the key could.
+    fn sign(signer: &CallbackSigner, body: &[u8], user_id: i64) -> String {
+        let mut bytes = body.to_vec();
+        bytes.extend_from_slice(&signer.tag(body, user_id));
+
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn round_trips_all_variants() {
+        let signer = make_signer("123:token");
+
+        for data in all_variants() {
+            for user_id in [USER_ID, 0, i64::MIN, i64::MAX] {
+                let encoded = signer.encode(data, user_id).unwrap();
+                assert!(encoded.len() <= MAX_ENCODED_LENGTH, "{data:?}: {encoded}");
+                assert_eq!(signer.decode(&encoded, user_id), Ok(data));
+            }
+        }
+    }
+
+    fn any_callback_data() -> impl Strategy<Value = CallbackData> {
+        prop_oneof![
+            Just(CallbackData::ActionSend),
+            any::<i64>().prop_map(CallbackData::SendTo),
+            any::<i32>().prop_map(CallbackData::DeletePost),
+            (any::<i32>(), any::<i64>())
+                .prop_map(|(message_id, chat_id)| CallbackData::ConfirmDraft(message_id, chat_id)),
+            any::<i32>().prop_map(CallbackData::ChangeDraftChat),
+            any::<i32>().prop_map(CallbackData::CancelDraft),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_any_data(data in any_callback_data(), user_id in any::<i64>()) {
+            let signer = make_signer("123:token");
+
+            let encoded = signer.encode(data, user_id).unwrap();
+            prop_assert!(encoded.len() <= MAX_ENCODED_LENGTH);
+            prop_assert!(!encoded.contains(TEXT_FORMAT_TAG_SEPARATOR));
+            prop_assert_eq!(signer.decode(&encoded, user_id), Ok(data));
+        }
+
+        #[test]
+        fn binds_data_to_the_user(
+            data in any_callback_data(),
+            user_id in any::<i64>(),
+            other_user_id in any::<i64>(),
+        ) {
+            prop_assume!(user_id != other_user_id);
+            let signer = make_signer("123:token");
+
+            let encoded = signer.encode(data, user_id).unwrap();
+            prop_assert_eq!(
+                signer.decode(&encoded, other_user_id),
+                Err(InvalidCallbackData::Invalid)
+            );
+        }
+
+        #[test]
+        fn decodes_text_format_send_to(chat_id in any::<i64>(), user_id in any::<i64>()) {
+            let signer = make_signer("123:token");
+            let payload = format!("s{chat_id}");
+            let tag = signer.tag(payload.as_bytes(), user_id);
+
+            let data = format!("{payload}.{}", URL_SAFE_NO_PAD.encode(tag));
+            prop_assert_eq!(signer.decode(&data, user_id), Ok(CallbackData::SendTo(chat_id)));
+        }
+
+        #[test]
+        fn never_accepts_unsigned_input(data in ".{0,64}") {
+            let signer = make_signer("123:token");
+
+            prop_assert!(signer.decode(&data, USER_ID).is_err());
+        }
+    }
+
+    #[test]
+    fn round_trips_varint_edge_cases() {
+        let cases = [
+            (0, 1),
+            (-1, 1),
+            (1, 1),
+            (63, 1),
+            (-64, 1),
+            (64, 2),
+            (-65, 2),
+            (i64::from(i32::MIN), 5),
+            (i64::from(i32::MAX), 5),
+            (i64::MIN, 10),
+            (i64::MAX, 10),
+        ];
+
+        for (value, length) in cases {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, value);
+            assert_eq!(bytes.len(), length, "{value}");
+
+            let mut input = bytes.as_slice();
+            assert_eq!(read_varint(&mut input), Some(value));
+            assert!(input.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_varints() {
+        assert_eq!(read_varint(&mut [].as_slice()), None);
+        assert_eq!(read_varint(&mut [0x80].as_slice()), None);
+        assert_eq!(read_varint(&mut [0xff; 11].as_slice()), None);
+    }
+
+    #[test]
+    fn reports_legacy_json_as_unsigned() {
+        let signer = make_signer("123:token");
+
+        for data in [
+            r#"{"action":"send"}"#,
+            r#"{"send_to":-100123}"#,
+            r#""send""#,
+            r#"{"action":"send_to","chat_id":-1001234567890}"#,
+            r#""a""#,
+            "{",
+        ] {
+            assert_eq!(
+                signer.decode(data, USER_ID),
+                Err(InvalidCallbackData::Unsigned)
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_text_format() {
+        let signer = make_signer("123:token");
+        let text_format = |payload: &str, user_id: i64| {
+            let tag = signer.tag(payload.as_bytes(), user_id);
+            format!("{payload}.{}", URL_SAFE_NO_PAD.encode(tag))
+        };
+
+        assert_eq!(
+            signer.decode(&text_format("a", USER_ID), USER_ID),
+            Ok(CallbackData::ActionSend)
+        );
+        assert_eq!(
+            signer.decode(&text_format("s-1001234567890", USER_ID), USER_ID),
+            Ok(CallbackData::SendTo(-1_001_234_567_890))
+        );
+        assert_eq!(
+            signer.decode(&text_format("s-100", USER_ID), USER_ID + 1),
+            Err(InvalidCallbackData::Invalid)
+        );
+        assert_eq!(
+            signer.decode(&text_format("x", USER_ID), USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+        // Only `a` and `s<chat id>` ever had the text format
+        for payload in ["", "a1", "s", "sabc", "s1.5", "d1"] {
+            assert_eq!(
+                signer.decode(&text_format(payload, USER_ID), USER_ID),
+                Err(InvalidCallbackData::Invalid),
+                "{payload}"
+            );
+        }
+        // Tags are checked before the payload, and base64 of the binary format never has a dot
+        assert_eq!(
+            signer.decode("s-1001234567890.AAAAAAAAAAAAAAAAAAAAAA", USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+        assert_eq!(
+            signer.decode("s-100.not base64!", USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+        assert_eq!(
+            signer.decode(".", USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+    }
+
+    #[test]
+    fn rejects_forged_tags() {
+        let signer = make_signer("123:token");
+        let encoded = signer.encode(CallbackData::SendTo(-100), USER_ID).unwrap();
+        let bytes = URL_SAFE_NO_PAD.decode(&encoded).unwrap();
+
+        // Every single changed byte, be it the action, a param or the tag, breaks the signature
+        for index in 0..bytes.len() {
+            let mut forged = bytes.clone();
+            forged[index] ^= 1;
+            assert_eq!(
+                signer.decode(&URL_SAFE_NO_PAD.encode(forged), USER_ID),
+                Err(InvalidCallbackData::Invalid)
+            );
+        }
+
+        let truncated = URL_SAFE_NO_PAD.encode(&bytes[..bytes.len() - 1]);
+        assert_eq!(
+            signer.decode(&truncated, USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+        assert_eq!(
+            signer.decode("", USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+        assert_eq!(
+            signer.decode("not base64!", USER_ID),
+            Err(InvalidCallbackData::Invalid)
+        );
+    }
+
+    #[test]
+    fn rejects_other_users_and_keys() {
+        let signer = make_signer("123:token");
+        let other_signer = make_signer("456:token");
+
+        for data in all_variants() {
+            let encoded = signer.encode(data, USER_ID).unwrap();
+            assert_eq!(
+                signer.decode(&encoded, USER_ID + 1),
+                Err(InvalidCallbackData::Invalid)
+            );
+            assert_eq!(
+                other_signer.decode(&encoded, USER_ID),
+                Err(InvalidCallbackData::Invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_signed_garbage() {
+        let signer = make_signer("123:token");
+
+        // Unknown version, unknown action, wrong number of params, a truncated varint and an
+        // out of range message id, all signed with the right key
+        let mut out_of_range = vec![VERSION, ACTION_DELETE_POST];
+        write_varint(&mut out_of_range, i64::from(i32::MAX) + 1);
+        let bodies = [
+            vec![VERSION + 1, ACTION_SEND],
+            vec![VERSION, 0],
+            vec![VERSION, ACTION_SEND, 0],
+            vec![VERSION, ACTION_SEND_TO],
+            vec![VERSION, ACTION_SEND_TO, 0x80],
+            out_of_range,
+        ];
+
+        for body in bodies {
+            assert_eq!(
+                signer.decode(&sign(&signer, &body, USER_ID), USER_ID),
+                Err(InvalidCallbackData::Invalid),
+                "{body:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+
+use crate::{
+    bot::{
+        callback_data::{read_varint, write_varint},
+        signing::{Signer, TAG_LENGTH},
+    },
+    config::AuthConfig,
+};
+
+const KEY_CONTEXT: &[u8] = b"anon chat link";
+const PAYLOAD_PREFIX: &str = "c_";
+
+/// Signs chat ids for `t.me/<bot>?start=c_<token>` links, so users can join only the chats an
+/// admin has shared a link to. Tokens are base64 of `[chat id varint][tag]`.
+pub struct ChatLinkSigner {
+    signer: Signer,
+}
+
+impl ChatLinkSigner {
+    pub fn new(auth: &AuthConfig) -> Self {
+        Self {
+            signer: Signer::new(auth, KEY_CONTEXT),
+        }
+    }
+
+    /// Makes the `/start` payload for the chat.
+    pub fn encode(&self, chat_id: i64) -> String {
+        let mut bytes = vec![];
+        write_varint(&mut bytes, chat_id);
+        let tag = self.signer.tag(&[&bytes]);
+        bytes.extend_from_slice(&tag);
+
+        format!("{PAYLOAD_PREFIX}{}", URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// Returns the chat id of a `/start` payload, or `None` when it's not a valid chat link.
+    pub fn decode(&self, payload: &str) -> Option<i64> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(payload.strip_prefix(PAYLOAD_PREFIX)?)
+            .ok()?;
+        let (body, tag) = bytes.split_at(bytes.len().checked_sub(TAG_LENGTH)?);
+        if !self.signer.verify(&[body], tag) {
+            return None;
+        }
+
+        let mut body = body;
+        let chat_id = read_varint(&mut body)?;
+
+        body.is_empty().then_some(chat_id)
+    }
+}
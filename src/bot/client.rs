@@ -1,52 +1,114 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
 use anyhow::Context;
-use reqwest::{Client as HttpClient, Response, Url, multipart::Form};
+use reqwest::{
+    Client as HttpClient, RequestBuilder, Response, StatusCode, Url,
+    header::CONTENT_TYPE,
+    multipart::{Form, Part},
+};
+use serde::de::DeserializeOwned;
+use tokio::sync::OnceCell;
 
 use crate::{
-    bot::entities::{SendAnimationPayload, SendPhotoPayload, SendStickerPayload},
+    bot::entities::{
+        ApiResponse, ChatMember, ErrorResponse, File, SendAnimationPayload, SendPhotoPayload,
+        SendStickerPayload, SentMessage, User, WebhookInfo, WebhookResponse,
+    },
     config::Config,
-    log::{debug, error},
+    log::{debug, error, info},
+    metrics::{TELEGRAM_API_CALLS, TELEGRAM_API_RATE_LIMITED, TELEGRAM_API_RETRIES},
 };
 
+const MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
 pub struct Client {
     base_url: Url,
+    file_base_url: Url,
     http_client: HttpClient,
+    api_available: AtomicBool,
+    bot_username: OnceCell<String>,
 }
 
 impl Client {
     pub fn new(config: &Config) -> anyhow::Result<Self> {
         Ok(Self {
-            base_url: format!("https://api.telegram.org/bot{}/", config.auth.bot_token)
-                .parse()
-                .context("Failed to create telegram api base url")?,
+            base_url: format!(
+                "https://api.telegram.org/bot{}/",
+                config.auth.bot_token.expose()
+            )
+            .parse()
+            .context("Failed to create telegram api base url")?,
+            file_base_url: format!(
+                "https://api.telegram.org/file/bot{}/",
+                config.auth.bot_token.expose()
+            )
+            .parse()
+            .context("Failed to create telegram file base url")?,
             http_client: HttpClient::builder()
                 .user_agent("Anon bot")
                 .build()
                 .context("Failed to create telegram http client")?,
+            api_available: AtomicBool::new(true),
+            bot_username: OnceCell::new(),
         })
     }
 
-    pub async fn setup(&self, config: &Config) -> anyhow::Result<()> {
+    /// Whether the last telegram api call got through. Errors caused by the request itself
+    /// (bad chat id, blocked bot and so on) don't count as unavailability.
+    pub fn is_api_available(&self) -> bool {
+        self.api_available.load(Ordering::Relaxed)
+    }
+
+    pub async fn setup(&self, config: &Config, secret_token: Option<&str>) -> anyhow::Result<()> {
+        self.set_webhook(config, secret_token).await?;
+        info!("Webhook is registered");
+
+        self.set_bot_profile(config).await
+    }
+
+    pub async fn set_webhook(
+        &self,
+        config: &Config,
+        secret_token: Option<&str>,
+    ) -> anyhow::Result<()> {
         let url = self
             .base_url
             .join("setWebhook")
             .context("Failed to create setup url")?;
 
-        let mut body = Form::new()
+        let mut body = Form::new().text("url", config.http.webhook_url()?.to_string());
+        if let Some(tls) = config.http.tls.as_ref() {
+            body = body.file("certificate", &tls.cert).await?;
+        }
+
+        if let Some(secret_token) = secret_token {
+            body = body.text("secret_token", secret_token.to_string());
+        }
+        body = body
             .text(
-                "url",
-                format!(
-                    "https://{}:{}/update",
-                    config.http.public_ip, config.http.port
-                ),
+                "allowed_updates",
+                serde_json::to_string(&config.setup.allowed_updates)?,
             )
-            .file("certificate", &config.http.tls.cert)
-            .await?;
-
-        if let Some(api_token) = config.auth.api_token.clone() {
-            body = body.text("secret_token", api_token);
+            .text(
+                "drop_pending_updates",
+                config.setup.drop_pending_updates.to_string(),
+            );
+        if let Some(max_connections) = config.setup.max_connections {
+            body = body.text("max_connections", max_connections.to_string());
         }
 
-        let response = self.http_client.post(url).multipart(body).send().await?;
+        let response = self
+            .http_client
+            .post(url)
+            .multipart(body)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
 
         if let Err(err) = response.error_for_status_ref() {
             let resp_body = response.text().await.ok();
@@ -55,29 +117,253 @@ impl Client {
                 resp_body.as_deref().unwrap_or("N/A")
             );
 
-            return Err(err.into());
+            return Err(err.without_url().into());
+        }
+
+        Ok(())
+    }
+
+    async fn set_bot_profile(&self, config: &Config) -> anyhow::Result<()> {
+        for language in &config.setup.languages {
+            let language_name = language.language_code.as_deref().unwrap_or("default");
+
+            self.call::<_, bool>(
+                "setMyCommands",
+                Some(&serde_json::json!({
+                    "commands": language.commands,
+                    "language_code": language.language_code,
+                })),
+            )
+            .await?;
+
+            if let Some(description) = language.description.as_deref() {
+                self.call::<_, bool>(
+                    "setMyDescription",
+                    Some(&serde_json::json!({
+                        "description": description,
+                        "language_code": language.language_code,
+                    })),
+                )
+                .await?;
+            }
+
+            if let Some(short_description) = language.short_description.as_deref() {
+                self.call::<_, bool>(
+                    "setMyShortDescription",
+                    Some(&serde_json::json!({
+                        "short_description": short_description,
+                        "language_code": language.language_code,
+                    })),
+                )
+                .await?;
+            }
+
+            info!("Bot profile for \"{language_name}\" language is registered");
         }
 
         Ok(())
     }
 
+    pub async fn get_webhook_info(&self) -> anyhow::Result<WebhookInfo> {
+        self.call::<(), _>("getWebhookInfo", None).await
+    }
+
+    pub async fn delete_webhook(&self, drop_pending_updates: bool) -> anyhow::Result<()> {
+        self.call::<_, bool>(
+            "deleteWebhook",
+            Some(&serde_json::json!({
+                "drop_pending_updates": drop_pending_updates,
+            })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_me(&self) -> anyhow::Result<User<'static>> {
+        let body = self
+            .send_json_request::<()>("getMe", None)
+            .await?
+            .bytes()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let response: ApiResponse<User<'_>> =
+            serde_json::from_slice(&body).context("Failed to parse \"getMe\" response")?;
+
+        Ok(response.result.into_owned())
+    }
+
+    /// Username of the bot for `t.me` links. It can't change while the bot runs, so `getMe` is
+    /// called only once.
+    pub async fn bot_username(&self) -> anyhow::Result<&str> {
+        self.bot_username
+            .get_or_try_init(|| async {
+                self.get_me()
+                    .await?
+                    .username
+                    .map(String::from)
+                    .context("Bot has no username to make a link with")
+            })
+            .await
+            .map(String::as_str)
+    }
+
+    pub async fn get_chat_member(&self, chat_id: i64, user_id: i64) -> anyhow::Result<ChatMember> {
+        self.call(
+            "getChatMember",
+            Some(&serde_json::json!({
+                "chat_id": chat_id,
+                "user_id": user_id,
+            })),
+        )
+        .await
+    }
+
+    pub async fn delete_message(&self, chat_id: i64, message_id: i32) -> anyhow::Result<()> {
+        self.call::<_, bool>(
+            "deleteMessage",
+            Some(&serde_json::json!({
+                "chat_id": chat_id,
+                "message_id": message_id,
+            })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn edit_message_text(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        text: &str,
+    ) -> anyhow::Result<()> {
+        self.send_json_request(
+            "editMessageText",
+            Some(&serde_json::json!({
+                "chat_id": chat_id,
+                "message_id": message_id,
+                "text": text,
+            })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn edit_message_caption(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        caption: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.send_json_request(
+            "editMessageCaption",
+            Some(&serde_json::json!({
+                "chat_id": chat_id,
+                "message_id": message_id,
+                "caption": caption,
+            })),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_file(&self, file_id: &str) -> anyhow::Result<File> {
+        self.call(
+            "getFile",
+            Some(&serde_json::json!({
+                "file_id": file_id,
+            })),
+        )
+        .await
+    }
+
+    /// Downloads a file by the path from `getFile`.
+    pub async fn download_file(&self, file_path: &str) -> anyhow::Result<Vec<u8>> {
+        let url = self
+            .file_base_url
+            .join(file_path)
+            .context("Failed to create file url")?;
+
+        let response = self.send_request("downloadFile", || self.http_client.get(url.clone()));
+        let body = response
+            .await?
+            .bytes()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+
+        Ok(body.to_vec())
+    }
+
+    pub async fn send_document(
+        &self,
+        chat_id: i64,
+        file_name: &str,
+        contents: Vec<u8>,
+        caption: Option<&str>,
+    ) -> anyhow::Result<SentMessage> {
+        let url = self.method_url("sendDocument")?;
+        let response = self
+            .send_request("sendDocument", || {
+                let document = Part::bytes(contents.clone()).file_name(file_name.to_string());
+                let mut form = Form::new()
+                    .text("chat_id", chat_id.to_string())
+                    .part("document", document);
+                if let Some(caption) = caption {
+                    form = form.text("caption", caption.to_string());
+                }
+
+                self.http_client.post(url.clone()).multipart(form)
+            })
+            .await?;
+        let body: ApiResponse<SentMessage> = response
+            .json()
+            .await
+            .context("Failed to parse \"sendDocument\" response")?;
+
+        Ok(body.result)
+    }
+
     pub async fn send_message(&self, payload: &impl serde::Serialize) {
         self.send_silent_json_request("sendMessage", Some(payload))
             .await
     }
 
-    pub async fn send_photo(&self, payload: SendPhotoPayload<'_>) {
-        self.send_silent_json_request("sendPhoto", Some(&payload))
-            .await;
+    /// Sends a plain text message, unlike [`Self::send_message`] reporting the result.
+    pub async fn send_text(&self, chat_id: i64, text: &str) -> anyhow::Result<SentMessage> {
+        self.call(
+            "sendMessage",
+            Some(&serde_json::json!({
+                "chat_id": chat_id,
+                "text": text,
+            })),
+        )
+        .await
     }
 
-    pub async fn send_animation(&self, payload: SendAnimationPayload<'_>) {
-        self.send_silent_json_request("sendAnimation", Some(&payload))
-            .await;
+    pub async fn send_photo(&self, payload: SendPhotoPayload<'_>) -> anyhow::Result<SentMessage> {
+        self.call("sendPhoto", Some(&payload)).await
+    }
+
+    pub async fn send_animation(
+        &self,
+        payload: SendAnimationPayload<'_>,
+    ) -> anyhow::Result<SentMessage> {
+        self.call("sendAnimation", Some(&payload)).await
+    }
+
+    pub async fn send_sticker(
+        &self,
+        payload: SendStickerPayload<'_>,
+    ) -> anyhow::Result<SentMessage> {
+        self.call("sendSticker", Some(&payload)).await
     }
 
-    pub async fn send_sticker(&self, payload: SendStickerPayload<'_>) {
-        self.send_silent_json_request("sendSticker", Some(&payload))
+    /// Sends a reply which didn't make it into the webhook response.
+    pub async fn send_reply(&self, reply: &WebhookResponse) {
+        self.send_silent_json_request(&reply.method, Some(&reply.params))
             .await;
     }
 
@@ -92,6 +378,20 @@ impl Client {
         .await;
     }
 
+    async fn call<T: serde::Serialize, R: DeserializeOwned>(
+        &self,
+        method: &str,
+        payload: Option<&T>,
+    ) -> anyhow::Result<R> {
+        let response = self.send_json_request(method, payload).await?;
+        let body: ApiResponse<R> = response
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse \"{method}\" response"))?;
+
+        Ok(body.result)
+    }
+
     async fn send_silent_json_request<T: serde::Serialize>(
         &self,
         method: &str,
@@ -110,24 +410,83 @@ impl Client {
         method: &str,
         payload: Option<&T>,
     ) -> anyhow::Result<Response> {
-        let url = self
-            .base_url
+        let url = self.method_url(method)?;
+
+        let body = match payload {
+            Some(payload) => {
+                let body_string = serde_json::to_string_pretty(payload)?;
+                debug!("Calling tg method \"{method}\" with body: {body_string}");
+                Some(serde_json::to_vec(payload)?)
+            }
+            None => None,
+        };
+
+        self.send_request(method, || {
+            let mut request = self.http_client.post(url.clone());
+            if let Some(body) = body.clone() {
+                request = request.header(CONTENT_TYPE, "application/json").body(body);
+            }
+
+            request
+        })
+        .await
+    }
+
+    fn method_url(&self, method: &str) -> anyhow::Result<Url> {
+        self.base_url
             .join(method)
-            .context("Failed to create sendMessage url")?;
+            .with_context(|| format!("Failed to create \"{method}\" url"))
+    }
 
-        let mut request = self.http_client.post(url);
-        if let Some(payload) = payload {
-            let body_string = serde_json::to_string_pretty(payload)?;
-            debug!("Calling tg method \"{method}\" with body: {body_string}");
-            request = request.json(&payload);
-        }
+    /// Sends the request, retrying it when telegram rate limits it. Requests are made anew for
+    /// every attempt, as their bodies can't always be reused.
+    async fn send_request(
+        &self,
+        method: &str,
+        make_request: impl Fn() -> RequestBuilder,
+    ) -> anyhow::Result<Response> {
+        let mut retries = 0;
+        let response = loop {
+            let response = match make_request().send().await {
+                Ok(response) => response,
+                Err(err) => {
+                    self.api_available.store(false, Ordering::Relaxed);
+                    TELEGRAM_API_CALLS
+                        .with_label_values(&[method, "error"])
+                        .inc();
+                    return Err(err.without_url())
+                        .context(format!("Send \"{method}\" request failed"));
+                }
+            };
+            let status = response.status();
+            TELEGRAM_API_CALLS
+                .with_label_values(&[method, status.as_str()])
+                .inc();
+            self.api_available.store(
+                !status.is_server_error() && status != StatusCode::UNAUTHORIZED,
+                Ordering::Relaxed,
+            );
+
+            if status != StatusCode::TOO_MANY_REQUESTS {
+                break response;
+            }
+            TELEGRAM_API_RATE_LIMITED.with_label_values(&[method]).inc();
+            if retries >= MAX_RETRIES {
+                break response;
+            }
+
+            let retry_after = retry_after(response).await;
+            info!("Telegram rate limited \"{method}\" request. Retrying in {retry_after:?}");
+            tokio::time::sleep(retry_after).await;
 
-        let response = request.send().await?;
+            retries += 1;
+            TELEGRAM_API_RETRIES.with_label_values(&[method]).inc();
+        };
 
         if let Err(err) = response.error_for_status_ref() {
             let resp_body = response.text().await.ok();
 
-            return Err(err).with_context(move || {
+            return Err(err.without_url()).with_context(move || {
                 format!(
                     "Send \"{method}\" request failed: {}",
                     resp_body.as_deref().unwrap_or("N/A")
@@ -138,3 +497,33 @@ impl Client {
         Ok(response)
     }
 }
+
+/// Whether the request may get through later: telegram wasn't reached or failed on its side.
+/// Errors caused by the request itself, such as a blocked bot, are final.
+pub fn is_transient_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|err| match err.status() {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => !err.is_decode() && !err.is_builder(),
+        })
+}
+
+/// Whether telegram refused the request as the bot may not do it, e.g. write to a user who never
+/// started the bot.
+pub fn is_forbidden_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|err| err.status() == Some(StatusCode::FORBIDDEN))
+}
+
+async fn retry_after(response: Response) -> Duration {
+    response
+        .json::<ErrorResponse>()
+        .await
+        .ok()
+        .and_then(|body| body.parameters?.retry_after)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+        .min(MAX_RETRY_AFTER)
+}
@@ -1,14 +1,16 @@
-use std::net::SocketAddr;
+use std::time::Duration;
 
 use crate::{
+    api_tokens::{self, ApiTokens},
+    cert,
     config::Config,
-    log::{debug, error, info},
+    log::{error, info},
+    metrics::{self, ANONYMOUS_MESSAGES, UPDATES_RECEIVED},
     state::AppState,
 };
 
 use anyhow::Context;
-use axum_server::tls_rustls::RustlsConfig;
-use futures::{FutureExt, future::maybe_done};
+use futures::future::maybe_done;
 use sd_notify::{NotifyState, notify};
 use tokio::{
     signal::unix::{SignalKind, signal},
@@ -19,17 +21,170 @@ use tokio_util::sync::CancellationToken;
 pub use api::entities;
 
 mod api;
+pub mod callback_data;
+pub mod chat_links;
 pub mod client;
+mod notices;
+mod publisher;
+mod server;
+mod signing;
+mod worker;
 
-pub fn setup(config: Config) -> anyhow::Result<()> {
+const STATUS_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn setup(config: Config, generate_cert: bool) -> anyhow::Result<()> {
+    if generate_cert {
+        let existing_file = config
+            .http
+            .tls
+            .iter()
+            .flat_map(|tls| [&tls.cert, &tls.key])
+            .find(|file| file.exists());
+        if let Some(file) = existing_file {
+            anyhow::bail!(
+                "{} already exists. Use `anon renew-cert` to replace the certificate",
+                file.display()
+            );
+        }
+
+        cert::generate_self_signed(&config.http)?;
+        info!("Generated self-signed certificate");
+    }
+    if let Some(tls) = config.http.tls.as_ref() {
+        cert::warn_if_expiring(&tls.cert);
+    }
+
+    block_on_client(&config, async |tg_client| {
+        let api_tokens = ApiTokens::load(&config.auth).await?;
+
+        tg_client.setup(&config, api_tokens.current()).await
+    })
+}
+
+pub fn renew_cert(config: Config) -> anyhow::Result<()> {
+    cert::generate_self_signed(&config.http)?;
+    info!("Generated self-signed certificate");
+
+    block_on_client(&config, async |tg_client| {
+        let api_tokens = ApiTokens::load(&config.auth).await?;
+        tg_client.set_webhook(&config, api_tokens.current()).await?;
+        info!("Webhook is registered with the new certificate");
+
+        Ok(())
+    })
+}
+
+/// Registers a new secret token. The running bot starts accepting it before telegram does and
+/// keeps accepting the previous one for `grace_period_secs`, so no update is rejected meanwhile.
+pub fn rotate_secret(config: Config, grace_period_secs: u64) -> anyhow::Result<()> {
+    let storage = config
+        .auth
+        .api_tokens_storage
+        .clone()
+        .context("`auth.api_tokens_storage` must be set to rotate the secret token")?;
+    let grace_period = i64::try_from(grace_period_secs)
+        .ok()
+        .and_then(chrono::TimeDelta::try_seconds)
+        .context("Grace period is too long")?;
+
+    block_on_client(&config, async |tg_client| {
+        let previous_tokens = ApiTokens::load(&config.auth).await?;
+        let mut api_tokens = previous_tokens.clone();
+        api_tokens.rotate(grace_period);
+        api_tokens
+            .save(&storage)
+            .await
+            .context("Failed to save api tokens")?;
+
+        info!("Waiting for the bot to pick the new token up");
+        tokio::time::sleep(api_tokens::RELOAD_INTERVAL * 2).await;
+
+        if let Err(err) = tg_client.set_webhook(&config, api_tokens.current()).await {
+            previous_tokens
+                .save(&storage)
+                .await
+                .context("Failed to restore api tokens")?;
+
+            return Err(err.context("Failed to register the new token, the old one is kept"));
+        }
+        info!(
+            "Webhook is registered with the new token, the old one retires in {grace_period_secs}s"
+        );
+
+        Ok(())
+    })
+}
+
+pub fn print_webhook_info(config: Config) -> anyhow::Result<()> {
+    let info = block_on_client(&config, async |tg_client| {
+        tg_client.get_webhook_info().await
+    })?;
+
+    let format_date = |date: i64| {
+        chrono::DateTime::from_timestamp(date, 0)
+            .map(|date| date.with_timezone(&chrono::Local).to_rfc2822())
+            .unwrap_or_else(|| date.to_string())
+    };
+
+    println!(
+        "URL: {}",
+        Some(info.url.as_str())
+            .filter(|url| !url.is_empty())
+            .unwrap_or("<not set>")
+    );
+    println!(
+        "Custom certificate: {}",
+        if info.has_custom_certificate {
+            "yes"
+        } else {
+            "no"
+        }
+    );
+    println!("Pending updates: {}", info.pending_update_count);
+    if let Some(ip_address) = info.ip_address.as_deref() {
+        println!("IP address: {ip_address}");
+    }
+    if let Some(max_connections) = info.max_connections {
+        println!("Max connections: {max_connections}");
+    }
+    if let Some(allowed_updates) = info.allowed_updates.as_ref() {
+        println!("Allowed updates: {}", allowed_updates.join(", "));
+    }
+    match (info.last_error_date, info.last_error_message.as_deref()) {
+        (Some(date), message) => println!(
+            "Last error: {} at {}",
+            message.unwrap_or("N/A"),
+            format_date(date)
+        ),
+        (None, _) => println!("Last error: none"),
+    }
+    if let Some(date) = info.last_synchronization_error_date {
+        println!("Last synchronization error at {}", format_date(date));
+    }
+
+    Ok(())
+}
+
+pub fn teardown(config: Config, drop_pending_updates: bool) -> anyhow::Result<()> {
+    block_on_client(&config, async |tg_client| {
+        tg_client.delete_webhook(drop_pending_updates).await?;
+        info!("Webhook is deleted");
+
+        Ok(())
+    })
+}
+
+fn block_on_client<T>(
+    config: &Config,
+    f: impl AsyncFnOnce(&client::Client) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?
         .block_on(async move {
-            let tg_client = client::Client::new(&config)?;
-            tg_client.setup(&config).await?;
+            let tg_client = client::Client::new(config)?;
 
-            Ok(())
+            f(&tg_client).await
         })
 }
 
@@ -47,18 +202,40 @@ async fn run_app(config: Config) -> anyhow::Result<()> {
 
     let web_handle = {
         let state = state.clone();
-        let handle = run_server(state.clone()).then(|web_result| async move {
-            let save_user_chats_result = state.save_user_chats().await;
+        let handle = async move {
+            let workers = tokio::spawn(worker::run_workers(state.clone()));
+            let notices = tokio::spawn(notices::run_member_notices(state.clone()));
+            let publisher = tokio::spawn(publisher::run_publisher(state.clone()));
+            let web_result = server::run_server(state.clone()).await;
+            // Stops the workers when the server failed by itself
+            state.cancellation_token().cancel();
+            let workers_result = workers.await.map_err(anyhow::Error::from);
+            let notices_result = notices.await.map_err(anyhow::Error::from);
+            let publisher_result = publisher.await.map_err(anyhow::Error::from);
+            state.tasks().close();
+            let drain_timeout = Duration::from_secs(state.config().http.shutdown_timeout_secs);
+            if tokio::time::timeout(drain_timeout, state.tasks().wait())
+                .await
+                .is_err()
+            {
+                info!("Background tasks didn't finish in {drain_timeout:?}");
+            }
+            let flush_result = state.flush_storages().await;
 
-            web_result.or(save_user_chats_result)
-        });
+            web_result
+                .and(workers_result)
+                .and(notices_result)
+                .and(publisher_result)
+                .and(flush_result)
+        };
 
         maybe_done(tokio::spawn(handle))
     };
     let mut web_handle = std::pin::pin!(web_handle);
-    let mut shutdown_rx = spawn_shutdown_signal_watcher(state.cancellation_token().clone())?;
+    let mut shutdown_rx = spawn_shutdown_signal_watcher()?;
 
-    notify(true, &[NotifyState::Ready])?;
+    notify(false, &[NotifyState::Ready])?;
+    spawn_systemd_notifier(state.cancellation_token().clone());
 
     tokio::select! {
         biased;
@@ -68,7 +245,7 @@ async fn run_app(config: Config) -> anyhow::Result<()> {
 
     state.cancellation_token().cancel();
 
-    tokio::select! {
+    let result = tokio::select! {
         biased;
         _ = &mut web_handle => {
             match web_handle.take_output() {
@@ -76,11 +253,15 @@ async fn run_app(config: Config) -> anyhow::Result<()> {
                 None => Ok(()),
             }
         },
-        _ = shutdown_rx.recv() => { info!("Terminating"); Ok(()) },
-    }
+        Some(()) = shutdown_rx.recv() => { info!("Terminating"); Ok(()) },
+    };
+
+    notify(false, &[NotifyState::Stopping])?;
+
+    result
 }
 
-fn spawn_shutdown_signal_watcher(ct: CancellationToken) -> anyhow::Result<Receiver<()>> {
+fn spawn_shutdown_signal_watcher() -> anyhow::Result<Receiver<()>> {
     let mut interrupt = signal(SignalKind::interrupt())?;
     let mut terminate = signal(SignalKind::terminate())?;
 
@@ -91,12 +272,11 @@ fn spawn_shutdown_signal_watcher(ct: CancellationToken) -> anyhow::Result<Receiv
             tokio::select! {
                 _ = interrupt.recv() => {},
                 _ = terminate.recv() => {},
-                _ = ct.cancelled() => break,
             }
 
             info!("Shutting down");
-            if let Err(err) = sender.send(()).await {
-                error!("Failed to send shutdown signal: {err}");
+            if sender.send(()).await.is_err() {
+                break;
             }
         }
     });
@@ -104,19 +284,36 @@ fn spawn_shutdown_signal_watcher(ct: CancellationToken) -> anyhow::Result<Receiv
     Ok(receiver)
 }
 
-async fn run_server(state: AppState) -> anyhow::Result<()> {
-    info!("Starting ...");
-    debug!("Run server with config: {:#?}", state.config());
+fn spawn_systemd_notifier(ct: CancellationToken) {
+    let mut watchdog_usec = 0;
+    let watchdog_enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
+    let interval = match watchdog_enabled {
+        true => Duration::from_micros(watchdog_usec / 2).min(STATUS_UPDATE_INTERVAL),
+        false => STATUS_UPDATE_INTERVAL,
+    };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], state.config().http.port));
-    let tls_config =
-        RustlsConfig::from_pem_file(&state.config().http.tls.cert, &state.config().http.tls.key)
-            .await?;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
 
-    tokio::select! {
-        res = axum_server::bind_rustls(addr, tls_config).serve(api::make_router(state.clone()).into_make_service()) => res,
-        _ = state.cancellation_token().cancelled() => Ok(())
-    }?;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = ct.cancelled() => break,
+            }
 
-    Ok(())
+            let status = format!(
+                "Handled {} updates, sent {} anonymous messages",
+                metrics::total(&UPDATES_RECEIVED),
+                metrics::total(&ANONYMOUS_MESSAGES),
+            );
+            let mut states = vec![NotifyState::Status(&status)];
+            if watchdog_enabled {
+                states.push(NotifyState::Watchdog);
+            }
+
+            if let Err(err) = notify(false, &states) {
+                error!("Failed to notify systemd: {err}");
+            }
+        }
+    });
 }
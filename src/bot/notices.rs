@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+use crate::{
+    log::{error, info},
+    state::AppState,
+};
+
+const NOTICE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Tells silent chats how many members can post anonymously. Notices go out on a timer and only
+/// carry the total, so they don't give away who has just registered.
+pub async fn run_member_notices(state: AppState) {
+    let mut ticker = tokio::time::interval(NOTICE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = state.cancellation_token().cancelled() => break,
+        }
+
+        let chats = state.chats().unannounced_members().await;
+        if chats.is_empty() {
+            continue;
+        }
+
+        for (chat_id, members) in chats {
+            let text = format!(
+                "Участников, которые могут отправлять анонимные сообщения в этот чат: {members}"
+            );
+            state
+                .tg_client()
+                .send_message(&serde_json::json!({
+                    "chat_id": chat_id,
+                    "text": text,
+                }))
+                .await;
+            state.chats().set_announced_members(chat_id, members).await;
+            info!("Announced {members} members in chat {chat_id}");
+        }
+
+        if let Err(err) = state.save_chats().await {
+            error!("Failed to save chats: {err:#}");
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use rand::seq::SliceRandom;
+
+use crate::{
+    bot::{
+        callback_data::CallbackData,
+        client,
+        entities::{
+            InlineKeyboardButton, InlineKeyboardMarkup, SendAnimationPayload, SendPhotoPayload,
+            SendStickerPayload, SentMessage,
+        },
+    },
+    log::{error, info, warn},
+    metrics::ANONYMOUS_MESSAGES,
+    outbox::{OutgoingContent, OutgoingMessage},
+    posts::{PartKind, Post, PostedPart},
+    sanitize,
+    state::AppState,
+};
+
+const RELEASE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Messages which failed to publish because of telegram or network are tried again this many
+/// times, waiting twice as long after every attempt.
+const MAX_PUBLISH_ATTEMPTS: u32 = 8;
+const FIRST_RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+
+const SANITIZE_FAILED_TEXT: &str = "Сообщение не опубликовано: не получилось убрать из файла данные об авторе. Файлы с комментариями и исправлениями, зашифрованные и повреждённые файлы отправить нельзя";
+const SEND_FAILED_TEXT: &str =
+    "Сообщение не опубликовано: Telegram не принял его. Попробуй отправить его ещё раз позже";
+
+/// Context of errors of documents which can't be stripped of metadata, as opposed to errors of
+/// telegram.
+#[derive(Debug)]
+struct SanitizeFailed;
+
+impl std::fmt::Display for SanitizeFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Failed to strip document")
+    }
+}
+
+/// What to tell the author when their message can't be published.
+pub fn publish_failed_text(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<SanitizeFailed>() {
+        Some(_) => SANITIZE_FAILED_TEXT,
+        None => SEND_FAILED_TEXT,
+    }
+}
+
+/// Posts the author's message to the chat and remembers the post, so the author can edit or
+/// delete it. Fails at the first part which can't be posted, parts before it are posted and
+/// remembered by then, and are skipped when publishing is tried again.
+pub async fn publish(
+    state: &AppState,
+    author_id: i64,
+    source_message_id: i32,
+    chat_id: i64,
+    contents: &[OutgoingContent],
+) -> anyhow::Result<()> {
+    let posted = state
+        .posts()
+        .find(author_id, source_message_id, i64::MIN)
+        .await
+        .filter(|post| post.chat_id == chat_id)
+        .map_or(0, |post| post.parts.len());
+    let contents = contents.get(posted..).unwrap_or_default();
+
+    let mut parts = vec![];
+    let result = send_contents(state, chat_id, contents, |media, kind, sent| {
+        ANONYMOUS_MESSAGES.with_label_values(&[media]).inc();
+        parts.push(PostedPart {
+            message_id: sent.message_id,
+            kind,
+        });
+    })
+    .await;
+
+    if !parts.is_empty() {
+        state
+            .posts()
+            .add(Post {
+                author_id,
+                source_message_id,
+                chat_id,
+                parts,
+                posted_at: chrono::Utc::now().timestamp(),
+            })
+            .await;
+        if let Err(err) = state.save_posts().await {
+            error!("Failed to save posts: {err:#}");
+        }
+    }
+
+    result
+}
+
+/// Shows the author their message exactly as it would be published, documents included.
+pub async fn preview(
+    state: &AppState,
+    author_chat_id: i64,
+    contents: &[OutgoingContent],
+) -> anyhow::Result<()> {
+    send_contents(state, author_chat_id, contents, |_, _, _| {}).await
+}
+
+/// Sends every part of the message, calling `on_sent` with its media type for the parts that made
+/// it. Stops at the first part which fails.
+async fn send_contents(
+    state: &AppState,
+    chat_id: i64,
+    contents: &[OutgoingContent],
+    mut on_sent: impl FnMut(&'static str, PartKind, SentMessage),
+) -> anyhow::Result<()> {
+    for content in contents {
+        match content {
+            OutgoingContent::Text { text } => {
+                let sent = state.tg_client().send_text(chat_id, text).await?;
+                on_sent("text", PartKind::Text, sent);
+            }
+            OutgoingContent::Photo { file_id, caption } => {
+                let sent = state
+                    .tg_client()
+                    .send_photo(SendPhotoPayload {
+                        chat_id,
+                        photo: file_id,
+                        caption: caption.as_deref(),
+                    })
+                    .await?;
+                on_sent("photo", PartKind::Media, sent);
+            }
+            OutgoingContent::Animation {
+                file_id,
+                duration,
+                width,
+                height,
+                caption,
+            } => {
+                let sent = state
+                    .tg_client()
+                    .send_animation(SendAnimationPayload {
+                        chat_id,
+                        animation: file_id,
+                        duration: Some(*duration),
+                        width: Some(*width),
+                        height: Some(*height),
+                        caption: caption.as_deref(),
+                    })
+                    .await?;
+                on_sent("animation", PartKind::Media, sent);
+            }
+            OutgoingContent::Sticker { file_id } => {
+                let sent = state
+                    .tg_client()
+                    .send_sticker(SendStickerPayload {
+                        chat_id,
+                        sticker: file_id,
+                    })
+                    .await?;
+                on_sent("sticker", PartKind::Other, sent);
+            }
+            OutgoingContent::Document {
+                file_id,
+                extension,
+                caption,
+            } => {
+                let sent = publish_document(state, chat_id, file_id, extension, caption.as_deref())
+                    .await?;
+                on_sent("document", PartKind::Media, sent);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the document, strips it and uploads it under a neutral name, as the original one
+/// can give the author away too.
+async fn publish_document(
+    state: &AppState,
+    chat_id: i64,
+    file_id: &str,
+    extension: &str,
+    caption: Option<&str>,
+) -> anyhow::Result<SentMessage> {
+    let file = state.tg_client().get_file(file_id).await?;
+    let file_path = file
+        .file_path
+        .context("Telegram gave no path to the file")?;
+    let contents = state.tg_client().download_file(&file_path).await?;
+    // A panic of the sanitizer is a document it can't handle too
+    let sanitized = tokio::task::spawn_blocking(move || sanitize::sanitize(&contents))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|sanitized| sanitized)
+        .context(SanitizeFailed)?;
+
+    state
+        .tg_client()
+        .send_document(chat_id, &format!("file.{extension}"), sanitized, caption)
+        .await
+}
+
+/// Publishes messages held back by mixing once they are due. Messages released together are
+/// shuffled, so a batch doesn't keep the order they were written in.
+pub async fn run_publisher(state: AppState) {
+    let mut ticker = tokio::time::interval(RELEASE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = state.cancellation_token().cancelled() => break,
+        }
+
+        if state.posts().prune(state.edit_window_start()).await
+            && let Err(err) = state.save_posts().await
+        {
+            error!("Failed to save posts: {err:#}");
+        }
+
+        let mut messages = state.outbox().due(chrono::Utc::now().timestamp()).await;
+        if messages.is_empty() {
+            continue;
+        }
+        messages.shuffle(&mut rand::rng());
+        let mut count = 0;
+
+        for message in messages {
+            let published = publish(
+                &state,
+                message.author_chat_id,
+                message.source_message_id,
+                message.chat_id,
+                &message.contents,
+            )
+            .await;
+
+            if let Err(err) = &published
+                && client::is_transient_error(err)
+                && message.attempts + 1 < MAX_PUBLISH_ATTEMPTS
+            {
+                let delay = retry_delay(message.attempts);
+                warn!(
+                    "Failed to publish message to chat {}, retrying in {delay} s: {err:#}",
+                    message.chat_id
+                );
+                state
+                    .outbox()
+                    .postpone(message.id, chrono::Utc::now().timestamp() + delay)
+                    .await;
+                if let Err(err) = state.save_outbox().await {
+                    error!("Failed to save outbox: {err:#}");
+                }
+                continue;
+            }
+
+            let title = state
+                .chats()
+                .get_chat(message.chat_id)
+                .await
+                .and_then(|chat| chat.title.clone());
+            let payload = match (published, title) {
+                (Err(err), _) => {
+                    error!(
+                        "Failed to publish message to chat {}: {err:#}",
+                        message.chat_id
+                    );
+                    serde_json::json!({
+                        "chat_id": message.author_chat_id,
+                        "text": publish_failed_text(&err),
+                    })
+                }
+                (Ok(()), title) => {
+                    count += 1;
+                    let text = match title {
+                        Some(title) => format!("Сообщение опубликовано в чате \"{title}\""),
+                        None => "Сообщение опубликовано".to_string(),
+                    };
+
+                    make_published_message(&state, &message, &text).unwrap_or_else(|err| {
+                        error!("Failed to make delete button: {err:#}");
+                        serde_json::json!({
+                            "chat_id": message.author_chat_id,
+                            "text": text,
+                        })
+                    })
+                }
+            };
+            state.tg_client().send_message(&payload).await;
+
+            state.outbox().remove(message.id).await;
+            if let Err(err) = state.save_outbox().await {
+                error!("Failed to save outbox: {err:#}");
+            }
+        }
+        if count > 0 {
+            info!("Published {count} held back messages");
+        }
+    }
+}
+
+/// Seconds to wait before the next attempt to publish a message.
+fn retry_delay(attempts: u32) -> i64 {
+    FIRST_RETRY_DELAY_SECS
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY_DELAY_SECS)
+}
+
+/// Confirmation for the author, with a button to take the message back.
+fn make_published_message(
+    state: &AppState,
+    message: &OutgoingMessage,
+    text: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let delete_button = InlineKeyboardButton {
+        text: "Удалить".to_string(),
+        callback_data: state.callback_signer().encode(
+            CallbackData::DeletePost(message.source_message_id),
+            message.author_chat_id,
+        )?,
+    };
+
+    Ok(serde_json::json!({
+        "chat_id": message.author_chat_id,
+        "text": text,
+        "reply_markup": InlineKeyboardMarkup {
+            inline_keyboard: vec![vec![delete_button]],
+        },
+    }))
+}
@@ -0,0 +1,245 @@
+use std::{
+    net::SocketAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use axum::Router;
+use axum_server::{Handle, tls_rustls::RustlsConfig};
+use socket2::{Domain, Socket, Type};
+use tokio::net::UnixListener;
+
+use crate::{
+    api_tokens::{self, ApiTokens},
+    bot::api,
+    cert,
+    config::{ListenAddress, TlsConfig},
+    log::{debug, error, info},
+    state::AppState,
+};
+
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const CERT_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const LISTEN_BACKLOG: i32 = 1024;
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+pub async fn run_server(state: AppState) -> anyhow::Result<()> {
+    info!("Starting ...");
+    debug!("Run server with config: {:#?}", state.config());
+
+    if let Some(file) = state.config().auth.api_tokens_storage.clone() {
+        spawn_api_tokens_watcher(state.clone(), file);
+    }
+
+    let router = api::make_router(state.clone());
+    let server = async {
+        match state.config().http.listen_address() {
+            ListenAddress::Tcp(addr) => serve_tcp(&state, addr, router).await,
+            ListenAddress::Unix(path) => serve_unix(&state, &path, router).await,
+        }
+    };
+
+    tokio::try_join!(server, run_admin_server(state.clone()))?;
+
+    Ok(())
+}
+
+async fn serve_tcp(state: &AppState, addr: SocketAddr, router: Router) -> anyhow::Result<()> {
+    let listener = bind_tcp(addr)?;
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+
+    let Some(tls) = state.config().http.tls.as_ref() else {
+        info!("Listening on {addr} without TLS");
+
+        axum_server::from_tcp(listener)
+            .handle(make_shutdown_handle(state))
+            .serve(make_service)
+            .await?;
+
+        return Ok(());
+    };
+
+    let tls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+    spawn_certificate_watcher(state.clone(), tls.clone(), tls_config.clone());
+
+    info!("Listening on {addr}");
+
+    axum_server::from_tcp_rustls(listener, tls_config)
+        .handle(make_shutdown_handle(state))
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+/// Binds tcp listener. Unspecified ipv6 address accepts ipv4 connections too.
+fn bind_tcp(addr: SocketAddr) -> anyhow::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind {addr}"))?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Serves plain http on a unix socket. TLS is expected to be terminated by a reverse proxy.
+async fn serve_unix(state: &AppState, path: &Path, router: Router) -> anyhow::Result<()> {
+    // Only a socket left by an earlier run is removed, anything else there is likely a typo
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to check {}", path.display()));
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind unix socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(UNIX_SOCKET_MODE))?;
+
+    info!("Listening on unix socket {}", path.display());
+
+    let ct = state.cancellation_token().clone();
+    let drain_timeout = Duration::from_secs(state.config().http.shutdown_timeout_secs);
+    let server = axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(ct.clone().cancelled_owned());
+
+    let result = tokio::select! {
+        res = server => res.map_err(Into::into),
+        _ = async { ct.cancelled().await; tokio::time::sleep(drain_timeout).await } => {
+            info!("Connections didn't finish in {drain_timeout:?}, closing them");
+            Ok(())
+        },
+    };
+
+    if let Err(err) = std::fs::remove_file(path) {
+        error!("Failed to remove socket {}: {err}", path.display());
+    }
+
+    result
+}
+
+/// Reloads the certificate when its file changes, e.g. after `anon renew-cert`, and periodically
+/// warns when it is about to expire.
+fn spawn_certificate_watcher(state: AppState, tls: TlsConfig, tls_config: RustlsConfig) {
+    tokio::spawn(async move {
+        let mut cert_modified_at = modified_at(&tls.cert);
+        let mut last_expiry_check = Instant::now();
+        cert::warn_if_expiring(&tls.cert);
+
+        let mut ticker = tokio::time::interval(CERT_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = state.cancellation_token().cancelled() => break,
+            }
+
+            let current_modified_at = modified_at(&tls.cert);
+            if current_modified_at != cert_modified_at {
+                cert_modified_at = current_modified_at;
+
+                match tls_config.reload_from_pem_file(&tls.cert, &tls.key).await {
+                    Ok(()) => info!("Reloaded certificate {}", tls.cert.display()),
+                    Err(err) => error!("Failed to reload certificate: {err}"),
+                }
+                cert::warn_if_expiring(&tls.cert);
+            }
+
+            if last_expiry_check.elapsed() >= CERT_EXPIRY_CHECK_INTERVAL {
+                last_expiry_check = Instant::now();
+                cert::warn_if_expiring(&tls.cert);
+            }
+        }
+    });
+}
+
+/// Reloads webhook secret tokens when `anon rotate-secret` changes them.
+fn spawn_api_tokens_watcher(state: AppState, file: PathBuf) {
+    tokio::spawn(async move {
+        let mut file_modified_at = modified_at(&file);
+
+        let mut ticker = tokio::time::interval(api_tokens::RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {},
+                _ = state.cancellation_token().cancelled() => break,
+            }
+
+            let current_modified_at = modified_at(&file);
+            if current_modified_at == file_modified_at || current_modified_at.is_none() {
+                continue;
+            }
+            file_modified_at = current_modified_at;
+
+            match ApiTokens::open(&file).await {
+                Ok(api_tokens) if api_tokens.is_empty() => {
+                    error!("Ignoring {} without tokens", file.display())
+                }
+                Ok(api_tokens) => {
+                    *state.api_tokens().write().await = api_tokens;
+                    info!("Reloaded api tokens");
+                }
+                Err(err) => error!("Failed to reload api tokens: {err:#}"),
+            }
+        }
+    });
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+async fn run_admin_server(state: AppState) -> anyhow::Result<()> {
+    let Some(admin_config) = state.config().http.admin.as_ref() else {
+        return Ok(());
+    };
+
+    info!("Starting admin server on {}", admin_config.bind);
+
+    axum_server::from_tcp(bind_tcp(admin_config.bind)?)
+        .handle(make_shutdown_handle(&state))
+        .serve(
+            api::make_admin_router()
+                .with_state(state.clone())
+                .into_make_service(),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Makes a server handle which stops accepting new connections on shutdown and waits for
+/// in-flight requests to finish during the configured drain timeout.
+fn make_shutdown_handle(state: &AppState) -> Handle {
+    let handle = Handle::new();
+
+    tokio::spawn({
+        let handle = handle.clone();
+        let ct = state.cancellation_token().clone();
+        let drain_timeout = Duration::from_secs(state.config().http.shutdown_timeout_secs);
+
+        async move {
+            ct.cancelled().await;
+
+            info!(
+                "Waiting up to {drain_timeout:?} for {} connections to finish",
+                handle.connection_count()
+            );
+            handle.graceful_shutdown(Some(drain_timeout));
+        }
+    });
+
+    handle
+}
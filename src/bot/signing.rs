@@ -0,0 +1,53 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::AuthConfig;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Bytes of the HMAC kept in signed data. Short enough for buttons and links, and still
+/// infeasible to guess online.
+pub const TAG_LENGTH: usize = 12;
+
+/// Makes and checks truncated HMAC-SHA256 tags with a key derived from the bot token, so it never
+/// leaves the server and changes along with the token.
+pub struct Signer {
+    key: Vec<u8>,
+}
+
+impl Signer {
+    /// Every kind of signed data gets its own context, so one can't pass for another.
+    pub fn new(auth: &AuthConfig, context: &[u8]) -> Self {
+        let mut mac = HmacSha256::new_from_slice(auth.bot_token.expose().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(context);
+
+        Self {
+            key: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Tag of the concatenated parts.
+    pub fn tag(&self, parts: &[&[u8]]) -> [u8; TAG_LENGTH] {
+        let tag = self.mac(parts).finalize().into_bytes();
+
+        tag[..TAG_LENGTH]
+            .try_into()
+            .expect("HMAC-SHA256 is longer than the tag")
+    }
+
+    /// Checks the tag in constant time.
+    pub fn verify(&self, parts: &[&[u8]], tag: &[u8]) -> bool {
+        tag.len() == TAG_LENGTH && self.mac(parts).verify_truncated_left(tag).is_ok()
+    }
+
+    fn mac(&self, parts: &[&[u8]]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+
+        mac
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::sync::{
+    Semaphore,
+    mpsc::{UnboundedReceiver, UnboundedSender, error::SendError, unbounded_channel},
+};
+use tokio_util::task::TaskTracker;
+
+use crate::{
+    bot::{
+        api,
+        entities::{UpdateMessage, WebhookResponse},
+    },
+    log::{FutureExt, error, info, logger, o},
+    metrics::REPLIES,
+    queue::QueuedUpdate,
+    state::AppState,
+};
+
+/// How long a user's worker waits for their next update before exiting.
+const SENDER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Channels of running workers by sender. Updates are sent and workers retire under the lock, so
+/// an update never goes to a worker which is exiting.
+type Senders = Arc<Mutex<HashMap<i64, UnboundedSender<(i64, Vec<u8>)>>>>;
+
+/// Handles queued updates until shutdown. Updates of one user are handled one by one in
+/// update id order, different users are handled in parallel up to `queue.workers` at a time.
+/// Updates left in the queue on shutdown are handled after the next start.
+pub async fn run_workers(state: AppState) {
+    let Some(mut receiver) = state.update_queue().take_receiver() else {
+        error!("Workers are already running");
+        return;
+    };
+
+    let semaphore = Arc::new(Semaphore::new(state.config().queue.workers));
+    let tracker = TaskTracker::new();
+    let senders = Senders::default();
+
+    loop {
+        let QueuedUpdate {
+            update_id,
+            sender_id,
+        } = tokio::select! {
+            Some(queued_update) = receiver.recv() => queued_update,
+            _ = state.cancellation_token().cancelled() => break,
+        };
+
+        // The update could be handled right before a crash, without being removed from the queue
+        if state.processed_updates().is_processed(update_id).await {
+            remove_from_queue(&state, update_id).await;
+            continue;
+        }
+
+        let update = match state.update_queue().load(update_id).await {
+            Ok(update) => update,
+            Err(err) => {
+                error!("Failed to load update {update_id}, dropping it: {err:#}");
+                remove_from_queue(&state, update_id).await;
+                continue;
+            }
+        };
+
+        // Updates without a sender have nothing to be ordered with
+        let key = sender_id.unwrap_or(update_id);
+        let mut running_senders = senders.lock().unwrap_or_else(PoisonError::into_inner);
+        let update = match running_senders.get(&key) {
+            Some(sender) => match sender.send((update_id, update)) {
+                Ok(()) => continue,
+                // The worker has stopped on shutdown
+                Err(SendError((_, update))) => update,
+            },
+            None => update,
+        };
+
+        let (sender, sender_receiver) = unbounded_channel();
+        sender.send((update_id, update)).expect("Receiver is alive");
+        running_senders.insert(key, sender);
+        drop(running_senders);
+        tracker.spawn(run_sender_worker(
+            state.clone(),
+            key,
+            sender_receiver,
+            senders.clone(),
+            semaphore.clone(),
+        ));
+    }
+
+    tracker.close();
+    let drain_timeout = Duration::from_secs(state.config().http.shutdown_timeout_secs);
+    if tokio::time::timeout(drain_timeout, tracker.wait())
+        .await
+        .is_err()
+    {
+        info!("Workers didn't finish in {drain_timeout:?}, their updates stay queued");
+    }
+}
+
+async fn run_sender_worker(
+    state: AppState,
+    key: i64,
+    mut receiver: UnboundedReceiver<(i64, Vec<u8>)>,
+    senders: Senders,
+    semaphore: Arc<Semaphore>,
+) {
+    loop {
+        let (update_id, update) = tokio::select! {
+            biased;
+            _ = state.cancellation_token().cancelled() => break,
+            update = tokio::time::timeout(SENDER_IDLE_TIMEOUT, receiver.recv()) => match update {
+                Ok(Some(update)) => update,
+                Ok(None) => break,
+                Err(_) => {
+                    let mut senders = senders.lock().unwrap_or_else(PoisonError::into_inner);
+                    // An update sent right as the timeout fired is handled first, so it keeps its
+                    // order with the ones a new worker gets
+                    if receiver.is_empty() {
+                        senders.remove(&key);
+                        break;
+                    }
+                    continue;
+                }
+            },
+        };
+
+        let Ok(_permit) = semaphore.acquire().await else {
+            break;
+        };
+
+        // Parsed once more here, as borrowed updates can't be passed between tasks. Updates which
+        // fail to parse are dropped
+        let result = match serde_json::from_slice::<UpdateMessage>(&update) {
+            Ok(update) => {
+                api::handle_update(&state, &update)
+                    .with_logger(logger().new(o!("update_id" => update_id)))
+                    .await
+            }
+            Err(err) => Err(err.into()),
+        };
+        match result {
+            Ok(Some(reply)) => send_reply(&state, update_id, reply).await,
+            Ok(None) => state.pending_replies().remove(update_id),
+            Err(err) => {
+                state.pending_replies().remove(update_id);
+                error!("Error during update {update_id} handling: {err:#}");
+            }
+        }
+
+        // The update is acknowledged already, so a failed one is not retried either
+        state.processed_updates().finish(update_id, true).await;
+        if let Err(err) = state.save_processed_updates().await {
+            error!("Failed to save processed updates: {err:#}");
+        }
+        remove_from_queue(&state, update_id).await;
+    }
+}
+
+/// Sends the reply in the webhook response when the request still waits, with the client otherwise.
+async fn send_reply(state: &AppState, update_id: i64, reply: WebhookResponse) {
+    match state.pending_replies().deliver(update_id, reply) {
+        Ok(()) => REPLIES.with_label_values(&["webhook"]).inc(),
+        Err(reply) => {
+            state.tg_client().send_reply(&reply).await;
+            REPLIES.with_label_values(&["api"]).inc();
+        }
+    }
+}
+
+async fn remove_from_queue(state: &AppState, update_id: i64) {
+    if let Err(err) = state.update_queue().remove(update_id).await {
+        error!("{err:#}");
+    }
+}
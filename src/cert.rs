@@ -0,0 +1,93 @@
+use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+
+use anyhow::Context;
+use chrono::TimeDelta;
+use rcgen::{CertificateParams, DnType, KeyPair, PKCS_RSA_SHA256, RsaKeySize};
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    config::HttpConfig,
+    log::{error, warn},
+};
+
+const CERT_VALIDITY_DAYS: i64 = 365;
+const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Generates a key pair and a self-signed certificate for the public host and writes them to the
+/// configured tls paths.
+pub fn generate_self_signed(http: &HttpConfig) -> anyhow::Result<()> {
+    let tls = http
+        .tls
+        .as_ref()
+        .context("`http.tls` must be set to generate a certificate")?;
+    let host = http
+        .public_host()
+        .context("`http.public_host` must be set to generate a certificate")?;
+
+    let mut params = CertificateParams::new(vec![host.clone()])
+        .context("Failed to use public host as certificate subject")?;
+    params.distinguished_name.push(DnType::CommonName, host);
+
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(CERT_VALIDITY_DAYS);
+
+    let key_pair = KeyPair::generate_rsa_for(&PKCS_RSA_SHA256, RsaKeySize::_2048)
+        .context("Failed to generate key pair")?;
+    let cert = params
+        .self_signed(&key_pair)
+        .context("Failed to sign certificate")?;
+
+    write_file(&tls.key, key_pair.serialize_pem().as_bytes(), 0o600)
+        .context("Failed to write private key")?;
+    write_file(&tls.cert, cert.pem().as_bytes(), 0o644).context("Failed to write certificate")?;
+
+    Ok(())
+}
+
+/// Returns the time left until the certificate expires, negative if it already has.
+pub fn time_until_expiry(cert_path: &Path) -> anyhow::Result<TimeDelta> {
+    let contents = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read certificate {}", cert_path.display()))?;
+    let (_, pem) =
+        x509_parser::pem::parse_x509_pem(&contents).context("Failed to parse certificate PEM")?;
+    let cert = pem.parse_x509().context("Failed to parse certificate")?;
+
+    let not_after = cert.validity().not_after.timestamp();
+
+    Ok(TimeDelta::seconds(
+        not_after - chrono::Utc::now().timestamp(),
+    ))
+}
+
+pub fn is_expiring(time_left: TimeDelta) -> bool {
+    time_left < TimeDelta::days(EXPIRY_WARNING_DAYS)
+}
+
+pub fn warn_if_expiring(cert_path: &Path) {
+    match time_until_expiry(cert_path) {
+        Ok(time_left) if time_left <= TimeDelta::zero() => error!(
+            "Certificate {} has expired. Replace it, e.g. with `anon renew-cert` for a self-signed one",
+            cert_path.display()
+        ),
+        Ok(time_left) if is_expiring(time_left) => warn!(
+            "Certificate {} expires in {} days. Replace it, e.g. with `anon renew-cert` for a self-signed one",
+            cert_path.display(),
+            time_left.num_days()
+        ),
+        Ok(_) => {}
+        Err(err) => error!("Failed to check certificate expiration: {err:#}"),
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    file.write_all(contents)?;
+
+    Ok(())
+}
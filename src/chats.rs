@@ -4,6 +4,7 @@ use std::{
 };
 
 use anyhow::Context;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{RwLock, RwLockReadGuard};
 
@@ -56,14 +57,78 @@ impl Chats {
             .collect()
     }
 
-    pub async fn add_user_chat(&self, user_id: i64, chat: &TgChat) -> bool {
+    pub async fn counts(&self) -> (usize, usize) {
+        let all_chats = self.0.read().await;
+
+        (all_chats.chats.len(), all_chats.users_to_chats.len())
+    }
+
+    /// Remembers the chat without members, so users can join it with a link later.
+    pub async fn add_chat(&self, chat: &TgChat<'_>) -> bool {
         let mut all_chats = self.0.write().await;
+        if all_chats.chats.contains_key(&chat.id) {
+            return false;
+        }
 
-        let saved_chat = all_chats.chats.entry(chat.id).or_insert_with(|| ChatInfo {
-            id: chat.id,
-            title: chat.title.clone(),
-            members: HashSet::new(),
-        });
+        all_chats.chats.insert(chat.id, ChatInfo::new(chat));
+
+        true
+    }
+
+    /// Adds the user to a chat which is already known. Returns `false` for unknown chats too.
+    pub async fn add_member(&self, user_id: i64, chat_id: i64) -> bool {
+        let mut all_chats = self.0.write().await;
+
+        let Some(saved_chat) = all_chats.chats.get_mut(&chat_id) else {
+            return false;
+        };
+        if !saved_chat.members.insert(user_id) {
+            return false;
+        }
+
+        all_chats
+            .users_to_chats
+            .entry(user_id)
+            .or_default()
+            .insert(chat_id)
+    }
+
+    /// Changes settings of the chat, remembering the chat if it's new.
+    pub async fn update_settings(&self, chat: &TgChat<'_>, update: impl FnOnce(&mut ChatSettings)) {
+        let mut all_chats = self.0.write().await;
+
+        let saved_chat = all_chats
+            .chats
+            .entry(chat.id)
+            .or_insert_with(|| ChatInfo::new(chat));
+        update(&mut saved_chat.settings);
+    }
+
+    /// Silent chats which got members since their last notice, with the number of members.
+    pub async fn unannounced_members(&self) -> Vec<(i64, usize)> {
+        let all_chats = self.0.read().await;
+
+        all_chats
+            .chats
+            .values()
+            .filter(|chat| chat.settings.silent && chat.members.len() > chat.announced_members)
+            .map(|chat| (chat.id, chat.members.len()))
+            .collect()
+    }
+
+    pub async fn set_announced_members(&self, chat_id: i64, members: usize) {
+        if let Some(chat) = self.0.write().await.chats.get_mut(&chat_id) {
+            chat.announced_members = members;
+        }
+    }
+
+    pub async fn add_user_chat(&self, user_id: i64, chat: &TgChat<'_>) -> bool {
+        let mut all_chats = self.0.write().await;
+
+        let saved_chat = all_chats
+            .chats
+            .entry(chat.id)
+            .or_insert_with(|| ChatInfo::new(chat));
 
         if !saved_chat.members.insert(user_id) {
             return false;
@@ -100,4 +165,85 @@ pub struct ChatInfo {
     pub id: i64,
     pub title: Option<String>,
     pub members: HashSet<i64>,
+    #[serde(default)]
+    pub settings: ChatSettings,
+    /// Number of members in the last notice of a silent chat
+    #[serde(default)]
+    pub announced_members: usize,
+}
+
+/// Members a chat needs before messages posted there are really anonymous.
+pub const DEFAULT_MIN_SENDERS: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatSettings {
+    /// Registration with /send doesn't announce the sender in the chat
+    #[serde(default)]
+    pub silent: bool,
+    /// Messages are refused while fewer members can post, as everyone would guess the sender
+    #[serde(default = "default_min_senders")]
+    pub min_senders: usize,
+    #[serde(default)]
+    pub mixing: Mixing,
+}
+
+/// Holds anonymous messages back, so the moment a message appears doesn't point at the member
+/// who was just typing.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Mixing {
+    #[default]
+    Off,
+    /// Every message waits for a random time up to `max_delay_secs`
+    Delay { max_delay_secs: u64 },
+    /// Messages are published together every `interval_secs`, shuffled
+    Batch { interval_secs: u64 },
+}
+
+impl Mixing {
+    /// When a message sent at `now` is published, `None` for right away.
+    pub fn release_at(self, now: i64) -> Option<i64> {
+        match self {
+            Self::Off => None,
+            Self::Delay { max_delay_secs } => {
+                Some(now + rand::rng().random_range(0..=max_delay_secs) as i64)
+            }
+            Self::Batch { interval_secs } => {
+                let interval = interval_secs.max(1) as i64;
+
+                Some((now / interval + 1) * interval)
+            }
+        }
+    }
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            silent: false,
+            min_senders: DEFAULT_MIN_SENDERS,
+            mixing: Mixing::default(),
+        }
+    }
+}
+
+fn default_min_senders() -> usize {
+    DEFAULT_MIN_SENDERS
+}
+
+impl ChatInfo {
+    fn new(chat: &TgChat<'_>) -> Self {
+        Self {
+            id: chat.id,
+            title: chat.title.as_deref().map(str::to_string),
+            members: HashSet::new(),
+            settings: ChatSettings::default(),
+            announced_members: 0,
+        }
+    }
+
+    /// Whether enough members can post for a message not to point at its sender.
+    pub fn is_anonymous(&self) -> bool {
+        self.members.len() >= self.settings.min_senders
+    }
 }
@@ -0,0 +1,358 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::TimeDelta;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    bot::client::Client as TelegramClient,
+    cert,
+    config::{
+        AuthConfig, Config, DialoguesConfig, HttpConfig, ListenAddress, LoggingConfig, PostsConfig,
+        QueueConfig, SetupConfig,
+    },
+    storage::{check_dir_writable, check_file_writable},
+};
+
+pub fn check_config(path: &Path) -> anyhow::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async move {
+            let mut problems = Problems::default();
+            run_checks(path, &mut problems).await;
+
+            problems.report(path)
+        })
+}
+
+#[derive(Default)]
+struct Problems(Vec<(String, String)>);
+
+impl Problems {
+    fn add(&mut self, key: &str, message: impl Display) {
+        self.0.push((key.to_string(), message.to_string()));
+    }
+
+    fn report(self, path: &Path) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            println!("{}: OK", path.display());
+            return Ok(());
+        }
+
+        println!("{}: found {} problem(s)", path.display(), self.0.len());
+        for (key, message) in &self.0 {
+            println!("  {key}: {message}");
+        }
+
+        anyhow::bail!("Config {} is invalid", path.display())
+    }
+}
+
+async fn run_checks(path: &Path, problems: &mut Problems) {
+    let raw = match Config::open_raw(path) {
+        Ok(raw) => raw,
+        Err(err) => {
+            problems.add("<file>", err);
+            return;
+        }
+    };
+
+    // Sections are deserialized one by one so that an error in one of them doesn't hide the rest
+    let auth = section::<AuthConfig>(&raw, "auth", problems);
+    let http = section::<HttpConfig>(&raw, "http", problems);
+    let log = section::<LoggingConfig>(&raw, "log", problems);
+    let chats_storage = section::<PathBuf>(&raw, "chats_storage", problems);
+    let updates_storage = section::<PathBuf>(&raw, "updates_storage", problems);
+    let outbox_storage = section::<PathBuf>(&raw, "outbox_storage", problems);
+    let dialogues = section::<DialoguesConfig>(&raw, "dialogues", problems);
+    let posts = section::<PostsConfig>(&raw, "posts", problems);
+    let queue = section::<QueueConfig>(&raw, "queue", problems);
+    let setup = match raw.get::<config::Value>("setup") {
+        Ok(_) => section::<SetupConfig>(&raw, "setup", problems),
+        Err(_) => None,
+    };
+
+    let token_looks_valid = auth.as_ref().is_some_and(|auth| check_auth(auth, problems));
+    if let Some(http) = http.as_ref() {
+        check_http(http, problems).await;
+    }
+    if let Some(log) = log.as_ref() {
+        check_log(log, problems).await;
+    }
+    if let Some(setup) = setup.as_ref() {
+        check_setup(setup, problems);
+    }
+    if let Some(queue) = queue.as_ref() {
+        check_queue(queue, problems).await;
+    }
+    if let Some(dialogues) = dialogues.as_ref()
+        && dialogues.timeout_secs < 60
+    {
+        problems.add("dialogues.timeout_secs", "must be at least 60");
+    }
+    let api_tokens_storage = auth
+        .as_ref()
+        .and_then(|auth| auth.api_tokens_storage.clone());
+    for (key, file) in [
+        ("chats_storage", chats_storage),
+        ("updates_storage", updates_storage),
+        ("outbox_storage", outbox_storage),
+        (
+            "dialogues.storage",
+            dialogues
+                .as_ref()
+                .map(|dialogues| dialogues.storage.clone()),
+        ),
+        ("posts.storage", posts.map(|posts| posts.storage)),
+        ("auth.api_tokens_storage", api_tokens_storage),
+    ] {
+        if let Some(file) = file {
+            check_storage(key, &file, problems).await;
+        }
+    }
+
+    if !token_looks_valid {
+        return;
+    }
+    if let Ok(config) = raw.try_deserialize::<Config>() {
+        check_bot_token_works(&config, problems).await;
+    }
+}
+
+fn section<T: DeserializeOwned>(
+    raw: &config::Config,
+    key: &str,
+    problems: &mut Problems,
+) -> Option<T> {
+    raw.get::<T>(key).map_err(|err| problems.add(key, err)).ok()
+}
+
+fn check_auth(auth: &AuthConfig, problems: &mut Problems) -> bool {
+    let bot_token_valid =
+        auth.bot_token
+            .expose()
+            .split_once(':')
+            .is_some_and(|(bot_id, secret)| {
+                !bot_id.is_empty()
+                    && bot_id.chars().all(|c| c.is_ascii_digit())
+                    && secret.len() >= 30
+                    && secret.chars().all(is_token_char)
+            });
+    if !bot_token_valid {
+        problems.add(
+            "auth.bot_token",
+            "doesn't look like a bot token, expected \"<bot id>:<secret>\" as given by @BotFather",
+        );
+    }
+
+    if let Some(api_token) = auth.api_token.as_ref() {
+        let api_token = api_token.expose();
+        if api_token.is_empty() || api_token.len() > 256 || !api_token.chars().all(is_token_char) {
+            problems.add(
+                "auth.api_token",
+                "must be 1-256 characters long and contain only A-Z, a-z, 0-9, _ and -",
+            );
+        }
+    }
+
+    bot_token_valid
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+async fn check_http(http: &HttpConfig, problems: &mut Problems) {
+    if http
+        .public_host
+        .as_ref()
+        .is_some_and(|host| host.trim().is_empty())
+    {
+        problems.add("http.public_host", "must not be empty");
+    }
+    match http.webhook_url() {
+        Ok(url) if url.scheme() != "https" => {
+            problems.add("http.public_url", "telegram only sends updates over https")
+        }
+        Ok(_) => {}
+        Err(err) => problems.add("http.public_url", format!("{err:#}")),
+    }
+    if http.allowed_ips.is_empty() {
+        problems.add(
+            "http.allowed_ips",
+            "must not be empty, use [0.0.0.0/0, ::/0] to accept updates from anywhere",
+        );
+    }
+    if http.port == 0 {
+        problems.add("http.port", "must be in range 1-65535");
+    }
+    match http.listen_address() {
+        ListenAddress::Tcp(addr) => {
+            // Telegram only calls https urls, so plain http is served to a proxy
+            if http.tls.is_none() && http.trusted_proxies.is_empty() {
+                problems.add(
+                    "http.trusted_proxies",
+                    "must list the reverse proxy when tls is off, otherwise every update comes from the proxy ip and is rejected",
+                );
+            }
+            if let Some(admin) = http.admin.as_ref()
+                && admin.bind.port() == addr.port()
+            {
+                problems.add(
+                    "http.admin.bind",
+                    "must not use the same port as the main server",
+                );
+            }
+        }
+        ListenAddress::Unix(path) => {
+            if !path.parent().is_some_and(Path::is_dir) {
+                problems.add(
+                    "http.bind",
+                    format!("directory for socket {} doesn't exist", path.display()),
+                );
+            }
+        }
+    }
+
+    let Some(tls) = http.tls.as_ref() else {
+        return;
+    };
+    let mut tls_files_exist = true;
+    for (key, file) in [("http.tls.cert", &tls.cert), ("http.tls.key", &tls.key)] {
+        if !file.is_file() {
+            problems.add(key, format!("file {} doesn't exist", file.display()));
+            tls_files_exist = false;
+        }
+    }
+    if !tls_files_exist {
+        return;
+    }
+    if let Err(err) = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await {
+        problems.add(
+            "http.tls",
+            format!("failed to load certificate and key, make sure they are valid PEM files and match each other: {err}"),
+        );
+    }
+    match cert::time_until_expiry(&tls.cert) {
+        Ok(time_left) if time_left <= TimeDelta::zero() => {
+            problems.add("http.tls.cert", "certificate has expired")
+        }
+        Ok(time_left) if cert::is_expiring(time_left) => println!(
+            "Warning: certificate {} expires in {} days",
+            tls.cert.display(),
+            time_left.num_days()
+        ),
+        Ok(_) => {}
+        Err(err) => problems.add("http.tls.cert", format!("{err:#}")),
+    }
+}
+
+fn check_setup(setup: &SetupConfig, problems: &mut Problems) {
+    if let Some(max_connections) = setup.max_connections
+        && !(1..=100).contains(&max_connections)
+    {
+        problems.add("setup.max_connections", "must be in range 1-100");
+    }
+
+    for (language_idx, language) in setup.languages.iter().enumerate() {
+        for (command_idx, command) in language.commands.iter().enumerate() {
+            let key = format!("setup.languages[{language_idx}].commands[{command_idx}]");
+
+            let name_valid = (1..=32).contains(&command.command.len())
+                && command
+                    .command
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !name_valid {
+                problems.add(
+                    &format!("{key}.command"),
+                    "must be 1-32 characters long and contain only a-z, 0-9 and _",
+                );
+            }
+            if !(1..=256).contains(&command.description.chars().count()) {
+                problems.add(
+                    &format!("{key}.description"),
+                    "must be 1-256 characters long",
+                );
+            }
+        }
+    }
+}
+
+async fn check_queue(queue: &QueueConfig, problems: &mut Problems) {
+    let dir = &queue.storage;
+    let result = match dir.exists() {
+        true if !dir.is_dir() => {
+            problems.add(
+                "queue.storage",
+                format!("{} is not a directory", dir.display()),
+            );
+            return;
+        }
+        true => check_dir_writable(dir).await,
+        // The directory is created on start
+        false => check_file_writable(dir).await,
+    };
+    if let Err(err) = result {
+        problems.add(
+            "queue.storage",
+            format!("{} is not writable: {err:#}", dir.display()),
+        );
+    }
+}
+
+async fn check_log(log: &LoggingConfig, problems: &mut Problems) {
+    let Some(file) = log.file.as_ref() else {
+        return;
+    };
+
+    if file.exists() && !file.is_file() {
+        problems.add("log.file", format!("{} is not a file", file.display()));
+        return;
+    }
+
+    if let Err(err) = check_file_writable(file).await {
+        problems.add(
+            "log.file",
+            format!("{} is not writable: {err:#}", file.display()),
+        );
+    }
+}
+
+async fn check_storage(key: &str, file: &Path, problems: &mut Problems) {
+    if let Err(err) = check_file_writable(file).await {
+        problems.add(key, format!("{} is not writable: {err:#}", file.display()));
+        return;
+    }
+
+    if !file.exists() {
+        return;
+    }
+
+    let parse_result = tokio::fs::read(file)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(serde_json::from_slice::<serde_json::Value>(&contents)?));
+    if let Err(err) = parse_result {
+        problems.add(key, format!("failed to read {}: {err:#}", file.display()));
+    }
+}
+
+async fn check_bot_token_works(config: &Config, problems: &mut Problems) {
+    let result = match TelegramClient::new(config) {
+        Ok(client) => client.get_me().await,
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(bot) => println!(
+            "Bot token belongs to @{}",
+            bot.username.as_deref().unwrap_or("<unknown>")
+        ),
+        Err(err) => problems.add("auth.bot_token", format!("getMe failed: {err:#}")),
+    }
+}
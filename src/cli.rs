@@ -19,6 +19,31 @@ impl Args {
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum Command {
-    Setup,
+    /// Register the webhook, commands and bot description in telegram
+    Setup {
+        /// Generate a self-signed certificate for `http.public_host` at the configured tls paths
+        #[arg(long)]
+        generate_cert: bool,
+    },
+    /// Regenerate the self-signed certificate and register the webhook with it
+    RenewCert,
+    /// Print the webhook status as seen by telegram
+    WebhookInfo,
+    /// Delete the webhook from telegram
+    Teardown {
+        /// Drop updates telegram hasn't delivered yet
+        #[arg(long)]
+        drop_pending_updates: bool,
+    },
+    /// Generate a new webhook secret token and register it in telegram. Needs
+    /// `auth.api_tokens_storage` so the running bot can pick the new token up
+    RotateSecret {
+        /// Seconds the previous token is still accepted for
+        #[arg(long, default_value_t = 3600)]
+        grace_period: u64,
+    },
+    /// Run the bot
     Run,
+    /// Validate the config and report every problem found
+    CheckConfig,
 }
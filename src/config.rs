@@ -1,8 +1,28 @@
 use std::path::Path;
 
-use serde::{Deserialize, Deserializer};
+use anyhow::Context;
+use ipnet::IpNet;
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize};
 use slog::Level;
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+const ENV_PREFIX: &str = "ANON";
+const DEFAULT_WEBHOOK_PATH: &str = "/update";
+/// Storages added after `chats_storage`, kept next to it unless set, so configs of older versions
+/// keep working.
+const DEFAULT_STORAGES: &[(&str, &str)] = &[
+    ("updates_storage", "updates.json"),
+    ("outbox_storage", "outbox.json"),
+    ("dialogues.storage", "dialogues.json"),
+    ("posts.storage", "posts.json"),
+    ("queue.storage", "queue"),
+];
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
@@ -10,39 +30,461 @@ pub struct Config {
     pub http: HttpConfig,
     pub log: LoggingConfig,
     pub chats_storage: PathBuf,
-    pub user_chats_storage: PathBuf,
+    /// Selected chats of versions before dialogues, imported once dialogues are first created
+    pub user_chats_storage: Option<PathBuf>,
+    /// Recently processed update ids, used to skip telegram's redeliveries
+    pub updates_storage: PathBuf,
+    /// Anonymous messages held back by mixing until they are published
+    pub outbox_storage: PathBuf,
+    pub dialogues: DialoguesConfig,
+    pub posts: PostsConfig,
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub setup: SetupConfig,
 }
 
 impl Config {
     pub fn open(path: &Path) -> anyhow::Result<Self> {
-        let cfg = config::Config::builder()
-            .add_source(config::File::from(path))
-            .build()?
-            .try_deserialize()?;
+        let cfg = Self::open_raw(path)?.try_deserialize()?;
 
         Ok(cfg)
     }
+
+    /// Merges the config file with environment overrides without deserializing it.
+    pub fn open_raw(path: &Path) -> Result<config::Config, config::ConfigError> {
+        let add_sources = |builder: config::ConfigBuilder<config::builder::DefaultState>| {
+            builder.add_source(config::File::from(path)).add_source(
+                config::Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+        };
+
+        let raw = add_sources(config::Config::builder()).build()?;
+        let Ok(chats_storage) = raw.get::<PathBuf>("chats_storage") else {
+            return Ok(raw);
+        };
+        let storage_dir = chats_storage.parent().unwrap_or(Path::new(""));
+        let mut builder = config::Config::builder();
+        for (key, file) in DEFAULT_STORAGES {
+            builder =
+                builder.set_default(*key, storage_dir.join(file).to_string_lossy().as_ref())?;
+        }
+
+        add_sources(builder).build()
+    }
 }
 
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "RawAuthConfig")]
 pub struct AuthConfig {
-    pub bot_token: String,
-    pub api_token: Option<String>,
+    pub bot_token: Secret,
+    pub api_token: Option<Secret>,
+    /// File with the tokens made by `anon rotate-secret`. Supersedes `api_token` once it exists
+    pub api_tokens_storage: Option<PathBuf>,
+    pub allow_unauthenticated: bool,
+}
+
+#[derive(Deserialize)]
+struct RawAuthConfig {
+    bot_token: Option<Secret>,
+    bot_token_file: Option<PathBuf>,
+    api_token: Option<Secret>,
+    api_token_file: Option<PathBuf>,
+    api_tokens_storage: Option<PathBuf>,
+    /// Accept updates without `X-Telegram-Bot-Api-Secret-Token` when no `api_token` is set
+    #[serde(default)]
+    allow_unauthenticated: bool,
+}
+
+impl TryFrom<RawAuthConfig> for AuthConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawAuthConfig) -> Result<Self, Self::Error> {
+        let bot_token = read_secret("bot_token", raw.bot_token, raw.bot_token_file)?
+            .context("Either `bot_token` or `bot_token_file` must be set")?;
+        let api_token = read_secret("api_token", raw.api_token, raw.api_token_file)?;
+        if api_token.is_none() && raw.api_tokens_storage.is_none() && !raw.allow_unauthenticated {
+            anyhow::bail!(
+                "`api_token` is not set, so anyone who can reach the webhook can post as any user. \
+                 Set `api_token` or `allow_unauthenticated: true`"
+            );
+        }
+
+        Ok(Self {
+            bot_token,
+            api_token,
+            api_tokens_storage: raw.api_tokens_storage,
+            allow_unauthenticated: raw.allow_unauthenticated,
+        })
+    }
+}
+
+fn read_secret(
+    name: &str,
+    value: Option<Secret>,
+    file: Option<PathBuf>,
+) -> anyhow::Result<Option<Secret>> {
+    match (value, file) {
+        (Some(_), Some(_)) => anyhow::bail!("Only one of `{name}` and `{name}_file` can be set"),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(file)) => {
+            let contents = std::fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read `{name}_file` {}", file.display()))?;
+
+            Ok(Some(Secret(contents.trim_end().to_string())))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// A string which is never printed by `Debug`, so config dumps don't leak tokens into logs.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct HttpConfig {
-    pub public_ip: String,
+    /// Host name or ip telegram sends updates to
+    #[serde(alias = "public_ip")]
+    pub public_host: Option<String>,
+    /// Full webhook url, e.g. `https://bot.example.com/anon/update` behind a reverse proxy.
+    /// Takes precedence over `public_host` and `port`
+    pub public_url: Option<String>,
+    /// Public port telegram sends updates to. Also used for listening when `bind` has no port
+    #[serde(default = "default_port")]
     pub port: u16,
-    pub tls: TlsConfig,
+    #[serde(default)]
+    pub bind: BindAddress,
+    /// Serve plain http when not set, e.g. when TLS is terminated by a load balancer
+    pub tls: Option<TlsConfig>,
+    /// Proxies whose `X-Forwarded-For` header is trusted, the local host by default. Unix socket
+    /// peers are always trusted
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Networks allowed to call the webhook, telegram's webhook subnets by default
+    #[serde(default = "default_allowed_ips")]
+    pub allowed_ips: Vec<IpNet>,
+    pub admin: Option<AdminConfig>,
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 }
 
-#[derive(Deserialize, Debug)]
+fn default_port() -> u16 {
+    8443
+}
+
+fn default_trusted_proxies() -> Vec<IpNet> {
+    ["127.0.0.0/8", "::1/128"]
+        .into_iter()
+        .map(|net| net.parse().expect("Loopback nets are valid"))
+        .collect()
+}
+
+fn default_allowed_ips() -> Vec<IpNet> {
+    // https://core.telegram.org/bots/webhooks#the-short-version
+    ["149.154.160.0/20", "91.108.4.0/22"]
+        .into_iter()
+        .map(|net| net.parse().expect("Telegram subnets are valid"))
+        .collect()
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+impl HttpConfig {
+    pub fn webhook_url(&self) -> anyhow::Result<Url> {
+        match (self.public_url.as_deref(), self.public_host.as_deref()) {
+            (Some(url), _) => url.parse().context("Invalid `http.public_url`"),
+            (None, Some(host)) => {
+                let host = match host.parse::<Ipv6Addr>() {
+                    Ok(_) => format!("[{host}]"),
+                    Err(_) => host.to_string(),
+                };
+
+                format!("https://{host}:{}{DEFAULT_WEBHOOK_PATH}", self.port)
+                    .parse()
+                    .context("Invalid `http.public_host`")
+            }
+            (None, None) => {
+                anyhow::bail!("Either `http.public_url` or `http.public_host` must be set")
+            }
+        }
+    }
+
+    /// Path the webhook is served on. Reverse proxies are expected to pass it unchanged.
+    pub fn webhook_path(&self) -> String {
+        self.public_url
+            .as_deref()
+            .and_then(|url| url.parse::<Url>().ok())
+            .map(|url| url.path().to_string())
+            .filter(|path| path != "/")
+            .unwrap_or_else(|| DEFAULT_WEBHOOK_PATH.to_string())
+    }
+
+    /// Host telegram connects to, used as the self-signed certificate subject.
+    pub fn public_host(&self) -> Option<String> {
+        match self.public_host.as_deref() {
+            Some(host) => Some(host.to_string()),
+            None => self.webhook_url().ok()?.host_str().map(|host| {
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string()
+            }),
+        }
+    }
+
+    pub fn listen_address(&self) -> ListenAddress {
+        match &self.bind {
+            BindAddress::Ip(ip) => ListenAddress::Tcp(SocketAddr::new(*ip, self.port)),
+            BindAddress::Socket(addr) => ListenAddress::Tcp(*addr),
+            BindAddress::Unix(path) => ListenAddress::Unix(path.clone()),
+        }
+    }
+}
+
+/// Address to listen on: `0.0.0.0`, `[::]`, `127.0.0.1:8443` or `unix:/run/anon/anon.sock`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub enum BindAddress {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Default for BindAddress {
+    fn default() -> Self {
+        Self::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl TryFrom<String> for BindAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(Self::Socket(addr));
+        }
+
+        let ip = value
+            .strip_prefix('[')
+            .and_then(|ip| ip.strip_suffix(']'))
+            .unwrap_or(&value);
+        ip.parse::<IpAddr>().map(Self::Ip).map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid bind address \"{value}\", expected ip, ip with port or unix:<path>"
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct TlsConfig {
     pub cert: PathBuf,
     pub key: PathBuf,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct AdminConfig {
+    pub bind: SocketAddr,
+}
+
+/// Per-user conversation state: the selected chat, drafts and /confirm.
+#[derive(Deserialize, Debug)]
+pub struct DialoguesConfig {
+    pub storage: PathBuf,
+    /// Drafts and chat selections left alone this long are dropped. The selected chat stays
+    #[serde(default = "default_dialogue_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_dialogue_timeout_secs() -> u64 {
+    24 * 60 * 60
+}
+
+/// Published anonymous messages their authors can still edit or delete.
+#[derive(Deserialize, Debug)]
+pub struct PostsConfig {
+    /// Links authors to their posts, so entries are kept only as long as the window lasts
+    pub storage: PathBuf,
+    /// How long after publishing authors can edit or delete a message
+    #[serde(
+        default = "default_edit_window_secs",
+        deserialize_with = "deserialize_edit_window_secs"
+    )]
+    pub edit_window_secs: u64,
+}
+
+/// Telegram doesn't let bots delete messages older than 48 hours.
+const MAX_EDIT_WINDOW_SECS: u64 = 48 * 60 * 60;
+
+fn default_edit_window_secs() -> u64 {
+    MAX_EDIT_WINDOW_SECS
+}
+
+fn deserialize_edit_window_secs<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secs = u64::deserialize(deserializer)?;
+    if !(1..=MAX_EDIT_WINDOW_SECS).contains(&secs) {
+        return Err(serde::de::Error::custom(format!(
+            "edit window must be in range 1-{MAX_EDIT_WINDOW_SECS} seconds, telegram doesn't let bots delete older messages"
+        )));
+    }
+
+    Ok(secs)
+}
+
+/// Updates are acknowledged once stored in the queue and handled by workers afterwards.
+#[derive(Deserialize, Debug)]
+pub struct QueueConfig {
+    /// Directory with queued updates
+    pub storage: PathBuf,
+    /// Updates handled concurrently
+    #[serde(
+        default = "default_queue_workers",
+        deserialize_with = "deserialize_positive"
+    )]
+    pub workers: usize,
+    /// Queued updates after which telegram is asked to redeliver new ones later
+    #[serde(
+        default = "default_queue_capacity",
+        deserialize_with = "deserialize_positive"
+    )]
+    pub capacity: usize,
+    /// How long a webhook request waits for the update to be handled, so the reply can be sent
+    /// in the response instead of a separate api call. 0 acknowledges updates right away
+    #[serde(default = "default_reply_wait_ms")]
+    pub reply_wait_ms: u64,
+}
+
+fn default_queue_workers() -> usize {
+    8
+}
+
+fn default_queue_capacity() -> usize {
+    10_000
+}
+
+/// Counts which stop the bot at zero: no update would ever be handled.
+fn deserialize_positive<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("must be at least 1")),
+        value => Ok(value),
+    }
+}
+
+fn default_reply_wait_ms() -> u64 {
+    0
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetupConfig {
+    #[serde(default = "default_allowed_updates")]
+    pub allowed_updates: Vec<String>,
+    pub max_connections: Option<u32>,
+    #[serde(default)]
+    pub drop_pending_updates: bool,
+    #[serde(default = "default_languages")]
+    pub languages: Vec<BotLanguageConfig>,
+}
+
+impl Default for SetupConfig {
+    fn default() -> Self {
+        Self {
+            allowed_updates: default_allowed_updates(),
+            max_connections: None,
+            drop_pending_updates: false,
+            languages: default_languages(),
+        }
+    }
+}
+
+fn default_allowed_updates() -> Vec<String> {
+    vec![
+        "message".to_string(),
+        "edited_message".to_string(),
+        "callback_query".to_string(),
+    ]
+}
+
+fn default_languages() -> Vec<BotLanguageConfig> {
+    vec![BotLanguageConfig {
+        language_code: None,
+        description: Some(
+            "Бот для отправки анонимных сообщений в групповые чаты. Отправь /send в общий чат с ботом, а затем напиши сюда сообщение."
+                .to_string(),
+        ),
+        short_description: Some("Анонимные сообщения в групповые чаты".to_string()),
+        commands: vec![
+            BotCommand {
+                command: "send".to_string(),
+                description: "Отправить анонимное сообщение".to_string(),
+            },
+            BotCommand {
+                command: "cancel".to_string(),
+                description: "Отменить черновик и выбор чата".to_string(),
+            },
+            BotCommand {
+                command: "confirm".to_string(),
+                description: "Показывать сообщение перед отправкой и спрашивать подтверждение"
+                    .to_string(),
+            },
+            BotCommand {
+                command: "delete".to_string(),
+                description: "Удалить своё анонимное сообщение, ответив на него".to_string(),
+            },
+            BotCommand {
+                command: "anonlink".to_string(),
+                description: "Ссылка для анонимных сообщений в этот чат".to_string(),
+            },
+        ],
+    }]
+}
+
+/// Bot profile shown to users with the given language. Profile without `language_code` is
+/// shown to everyone else.
+#[derive(Deserialize, Debug)]
+pub struct BotLanguageConfig {
+    pub language_code: Option<String>,
+    pub description: Option<String>,
+    pub short_description: Option<String>,
+    #[serde(default)]
+    pub commands: Vec<BotCommand>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BotCommand {
+    pub command: String,
+    pub description: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct LoggingConfig {
     pub term: bool,
@@ -75,3 +517,70 @@ where
 
     deserializer.deserialize_str(LevelVisitor)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen_address(bind: &str) -> anyhow::Result<ListenAddress> {
+        let http: HttpConfig =
+            serde_json::from_value(serde_json::json!({ "port": 8443, "bind": bind }))?;
+
+        Ok(http.listen_address())
+    }
+
+    fn tcp(addr: &str) -> SocketAddr {
+        match listen_address(addr).unwrap() {
+            ListenAddress::Tcp(addr) => addr,
+            ListenAddress::Unix(path) => panic!("{addr} is parsed as unix socket {path:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_socket_addresses() {
+        assert_eq!(tcp("[::]:443"), "[::]:443".parse().unwrap());
+        assert_eq!(tcp("[::1]:8080"), "[::1]:8080".parse().unwrap());
+        assert_eq!(tcp("127.0.0.1:80"), "127.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
+    fn uses_port_for_bare_ips() {
+        assert_eq!(tcp("0.0.0.0"), "0.0.0.0:8443".parse().unwrap());
+        assert_eq!(tcp("192.0.2.1"), "192.0.2.1:8443".parse().unwrap());
+        assert_eq!(tcp("::"), "[::]:8443".parse().unwrap());
+        assert_eq!(tcp("[::]"), "[::]:8443".parse().unwrap());
+        assert_eq!(tcp("[::1]"), "[::1]:8443".parse().unwrap());
+    }
+
+    #[test]
+    fn parses_unix_sockets() {
+        match listen_address("unix:/run/anon/anon.sock").unwrap() {
+            ListenAddress::Unix(path) => assert_eq!(path, Path::new("/run/anon/anon.sock")),
+            ListenAddress::Tcp(addr) => panic!("unix socket is parsed as {addr}"),
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_addresses() {
+        for bind in [
+            "",
+            "localhost",
+            "localhost:8443",
+            "192.0.2.1:",
+            "[::1",
+            "/run/anon.sock",
+        ] {
+            assert!(listen_address(bind).is_err(), "{bind}");
+        }
+    }
+
+    #[test]
+    fn binds_to_all_ipv4_addresses_by_default() {
+        let http: HttpConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+
+        assert!(matches!(
+            http.listen_address(),
+            ListenAddress::Tcp(addr) if addr == "0.0.0.0:8443".parse().unwrap()
+        ));
+    }
+}
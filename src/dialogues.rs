@@ -0,0 +1,207 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{config::Config, log::info, outbox::OutgoingContent};
+
+/// Where every user is in the conversation with the bot. Kept on disk, so a restart loses neither
+/// the selected chat nor drafts.
+pub struct Dialogues {
+    /// Drafts and chat selections left alone this long are dropped, the selected chat stays
+    timeout_secs: i64,
+    dialogues: Mutex<HashMap<i64, Dialogue>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Dialogue {
+    state: DialogueState,
+    /// Unix timestamp of the last transition
+    updated_at: i64,
+    /// Set with /confirm, outlives timeouts
+    #[serde(default)]
+    confirms: bool,
+}
+
+impl Dialogue {
+    fn new(now: i64) -> Self {
+        Self {
+            state: DialogueState::Idle,
+            updated_at: now,
+            confirms: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DialogueState {
+    #[default]
+    Idle,
+    /// The chat selection is shown. Holds the draft when the user is moving it to another chat
+    ChoosingChat { draft: Option<Draft> },
+    /// Messages of the user go to the chat
+    Composing { chat_id: i64 },
+    /// The draft is shown to the user and waits to be confirmed
+    Confirming { draft: Draft },
+}
+
+impl DialogueState {
+    /// What the dialogue comes to once it times out: the draft is dropped, and messages keep
+    /// going to the selected chat.
+    fn expired(&self) -> Self {
+        match self.chat_id() {
+            Some(chat_id) => Self::Composing { chat_id },
+            None => Self::Idle,
+        }
+    }
+
+    /// Chat the user's messages go to.
+    pub fn chat_id(&self) -> Option<i64> {
+        match self {
+            Self::Composing { chat_id } => Some(*chat_id),
+            Self::Confirming { draft } => Some(draft.chat_id),
+            Self::Idle | Self::ChoosingChat { .. } => None,
+        }
+    }
+
+    /// The draft made from the message, if the user is still working on it.
+    pub fn draft(&self, source_message_id: i32) -> Option<&Draft> {
+        match self {
+            Self::ChoosingChat { draft: Some(draft) } | Self::Confirming { draft } => {
+                Some(draft).filter(|draft| draft.source_message_id == source_message_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Draft {
+    pub chat_id: i64,
+    /// Message in the author's private chat the draft is made from
+    pub source_message_id: i32,
+    pub contents: Vec<OutgoingContent>,
+}
+
+impl Dialogues {
+    /// Opens the storage. On the first start dialogues are made from the selected chats kept by
+    /// older versions.
+    pub async fn open(config: &Config) -> anyhow::Result<Self> {
+        let file = &config.dialogues.storage;
+        let dialogues = match file.exists() {
+            true => {
+                let contents = tokio::fs::read(file).await?;
+
+                serde_json::from_slice(&contents)?
+            }
+            false => import_user_chats(config.user_chats_storage.as_deref())
+                .await
+                .context("Failed to import selected chats")?,
+        };
+
+        Ok(Self {
+            timeout_secs: i64::try_from(config.dialogues.timeout_secs).unwrap_or(i64::MAX),
+            dialogues: Mutex::new(dialogues),
+        })
+    }
+
+    /// State of the user's dialogue, without the draft once it has timed out.
+    pub async fn get(&self, user_id: i64) -> DialogueState {
+        let now = chrono::Utc::now().timestamp();
+
+        self.dialogues
+            .lock()
+            .await
+            .get(&user_id)
+            .map(|dialogue| match self.is_expired(dialogue, now) {
+                true => dialogue.state.expired(),
+                false => dialogue.state.clone(),
+            })
+            .unwrap_or_default()
+    }
+
+    pub async fn set(&self, user_id: i64, state: DialogueState) {
+        let now = chrono::Utc::now().timestamp();
+        let mut dialogues = self.dialogues.lock().await;
+
+        let dialogue = dialogues
+            .entry(user_id)
+            .or_insert_with(|| Dialogue::new(now));
+        dialogue.state = state;
+        dialogue.updated_at = now;
+
+        self.prune(&mut dialogues, now);
+    }
+
+    pub async fn confirms(&self, user_id: i64) -> bool {
+        self.dialogues
+            .lock()
+            .await
+            .get(&user_id)
+            .is_some_and(|dialogue| dialogue.confirms)
+    }
+
+    /// Turns confirmation on or off. Returns whether it is on now.
+    pub async fn toggle_confirmation(&self, user_id: i64) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut dialogues = self.dialogues.lock().await;
+
+        let dialogue = dialogues
+            .entry(user_id)
+            .or_insert_with(|| Dialogue::new(now));
+        dialogue.confirms = !dialogue.confirms;
+
+        dialogue.confirms
+    }
+
+    pub async fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let contents = { serde_json::to_vec(&*self.dialogues.lock().await) }?;
+        tokio::fs::write(file, contents)
+            .await
+            .context("Failed to write dialogues")?;
+
+        Ok(())
+    }
+
+    fn is_expired(&self, dialogue: &Dialogue, now: i64) -> bool {
+        now.saturating_sub(dialogue.updated_at) > self.timeout_secs
+    }
+
+    /// Drops drafts of timed out dialogues, so they don't stay on disk forever, and forgets users
+    /// with nothing left to remember.
+    fn prune(&self, dialogues: &mut HashMap<i64, Dialogue>, now: i64) {
+        for dialogue in dialogues.values_mut() {
+            if self.is_expired(dialogue, now) {
+                dialogue.state = dialogue.state.expired();
+            }
+        }
+        dialogues.retain(|_, dialogue| {
+            dialogue.confirms || !matches!(dialogue.state, DialogueState::Idle)
+        });
+    }
+}
+
+async fn import_user_chats(file: Option<&Path>) -> anyhow::Result<HashMap<i64, Dialogue>> {
+    let now = chrono::Utc::now().timestamp();
+    let mut dialogues = HashMap::new();
+
+    if let Some(file) = file.filter(|file| file.exists()) {
+        let contents = tokio::fs::read(file).await?;
+        let user_chats: HashMap<i64, i64> = serde_json::from_slice(&contents)?;
+        for (user_id, chat_id) in user_chats {
+            dialogues.insert(
+                user_id,
+                Dialogue {
+                    state: DialogueState::Composing { chat_id },
+                    updated_at: now,
+                    confirms: false,
+                },
+            );
+        }
+        info!("Imported selected chats from {}", file.display());
+    }
+
+    Ok(dialogues)
+}
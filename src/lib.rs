@@ -0,0 +1,20 @@
+#![allow(deprecated)]
+
+mod api_tokens;
+pub mod bot;
+mod cert;
+mod chats;
+pub mod check;
+pub mod cli;
+pub mod config;
+mod dialogues;
+pub mod log;
+mod metrics;
+mod outbox;
+mod posts;
+mod queue;
+mod replies;
+mod sanitize;
+mod state;
+mod storage;
+mod updates;
@@ -1,27 +1,43 @@
-#![allow(deprecated)]
+use std::path::Path;
 
-use crate::{
+use anon::{
+    bot, check,
     cli::{Args, Command},
     config::Config,
+    log,
 };
 
-mod bot;
-mod chats;
-mod cli;
-mod config;
-mod log;
-mod state;
-
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let config = Config::open(&args.config)?;
-
-    let _global_logger = log::init(&config)?;
 
-    match args.command {
-        Some(Command::Setup) => bot::setup(config)?,
-        Some(Command::Run) | None => bot::start(config)?,
+    match args.command.unwrap_or(Command::Run) {
+        // Reports config problems itself instead of failing on the first one
+        Command::CheckConfig => check::check_config(&args.config),
+        Command::Setup { generate_cert } => {
+            with_config(&args.config, |config| bot::setup(config, generate_cert))
+        }
+        Command::RenewCert => with_config(&args.config, bot::renew_cert),
+        Command::WebhookInfo => with_config(&args.config, bot::print_webhook_info),
+        Command::Teardown {
+            drop_pending_updates,
+        } => with_config(&args.config, |config| {
+            bot::teardown(config, drop_pending_updates)
+        }),
+        Command::RotateSecret { grace_period } => with_config(&args.config, |config| {
+            bot::rotate_secret(config, grace_period)
+        }),
+        Command::Run => with_config(&args.config, bot::start),
     }
+}
+
+/// Opens the config and keeps the logger it sets up for the whole command.
+fn with_config(
+    file: &Path,
+    command: impl FnOnce(Config) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let config = Config::open(file)?;
+
+    let _global_logger = log::init(&config)?;
 
-    Ok(())
+    command(config)
 }
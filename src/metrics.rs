@@ -0,0 +1,148 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder, core::Collector,
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+};
+
+pub static UPDATES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_updates_received_total",
+        "Updates received from telegram by update type",
+        &["type"]
+    )
+    .expect("Failed to register updates counter")
+});
+
+pub static UPDATE_PARSE_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "anon_update_parse_failures_total",
+        "Updates that could not be parsed and were skipped"
+    )
+    .expect("Failed to register parse failures counter")
+});
+
+pub static DUPLICATE_UPDATES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "anon_duplicate_updates_total",
+        "Updates redelivered by telegram and skipped"
+    )
+    .expect("Failed to register duplicate updates counter")
+});
+
+pub static QUEUED_UPDATES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "anon_queued_updates",
+        "Acknowledged updates waiting for a worker"
+    )
+    .expect("Failed to register queued updates gauge")
+});
+
+pub static OUTBOX_MESSAGES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "anon_outbox_messages",
+        "Anonymous messages held back by mixing"
+    )
+    .expect("Failed to register outbox messages gauge")
+});
+
+pub static WEBHOOK_REJECTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "anon_webhook_rejected_total",
+        "Webhook requests rejected because of the client ip"
+    )
+    .expect("Failed to register rejected requests counter")
+});
+
+pub static ANONYMOUS_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_anonymous_messages_total",
+        "Anonymous messages forwarded by media type",
+        &["media"]
+    )
+    .expect("Failed to register anonymous messages counter")
+});
+
+pub static REFUSED_MESSAGES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "anon_refused_messages_total",
+        "Anonymous messages refused because too few members of the target chat can post"
+    )
+    .expect("Failed to register refused messages counter")
+});
+
+pub static REPLIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_replies_total",
+        "Primary replies to updates by the way they were sent: webhook response or api call",
+        &["via"]
+    )
+    .expect("Failed to register replies counter")
+});
+
+pub static TELEGRAM_API_CALLS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_telegram_api_calls_total",
+        "Telegram api calls by method and response status",
+        &["method", "status"]
+    )
+    .expect("Failed to register telegram api calls counter")
+});
+
+pub static TELEGRAM_API_RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_telegram_api_rate_limited_total",
+        "Telegram api calls answered with 429 Too Many Requests",
+        &["method"]
+    )
+    .expect("Failed to register rate limit counter")
+});
+
+pub static TELEGRAM_API_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "anon_telegram_api_retries_total",
+        "Retried telegram api calls",
+        &["method"]
+    )
+    .expect("Failed to register retries counter")
+});
+
+pub static WEBHOOK_HANDLING_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "anon_webhook_handling_seconds",
+        "End-to-end webhook request handling latency",
+        vec![
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0
+        ]
+    )
+    .expect("Failed to register webhook latency histogram")
+});
+
+pub static REGISTERED_CHATS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("anon_registered_chats", "Chats known to the bot")
+        .expect("Failed to register chats gauge")
+});
+
+pub static REGISTERED_USERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "anon_registered_users",
+        "Users that can send anonymous messages to at least one chat"
+    )
+    .expect("Failed to register users gauge")
+});
+
+pub fn encode() -> anyhow::Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+pub fn total(counter: &IntCounterVec) -> u64 {
+    counter
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
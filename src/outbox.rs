@@ -0,0 +1,226 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{bot::entities::Message, metrics::OUTBOX_MESSAGES, sanitize};
+
+/// Anonymous messages held back by mixing until their release time. Kept on disk, so a restart
+/// doesn't lose them.
+pub struct Outbox(Mutex<Vec<OutgoingMessage>>);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutgoingMessage {
+    pub id: u64,
+    pub chat_id: i64,
+    /// Private chat of the author, who is told once the message is published
+    pub author_chat_id: i64,
+    /// Message in the author's private chat, by which the author edits or deletes the post
+    #[serde(default)]
+    pub source_message_id: i32,
+    /// Unix timestamp
+    pub release_at: i64,
+    pub contents: Vec<OutgoingContent>,
+    /// Failed attempts to publish the message
+    #[serde(default)]
+    pub attempts: u32,
+    /// Set while the publisher is posting the message. Not kept on disk, so messages a crash
+    /// interrupted are published again
+    #[serde(skip)]
+    pub publishing: bool,
+}
+
+/// Where the author's message is, for the author editing or deleting it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HeldBack {
+    /// Waits for its release time, changes still get into the chat
+    Waiting,
+    /// Is being posted right now, too late to change
+    Publishing,
+    /// Not in the outbox, either published or never held back
+    No,
+}
+
+/// Part of a message, posted with its own api call.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutgoingContent {
+    Text {
+        text: String,
+    },
+    Photo {
+        file_id: String,
+        caption: Option<String>,
+    },
+    Animation {
+        file_id: String,
+        duration: i64,
+        width: i64,
+        height: i64,
+        caption: Option<String>,
+    },
+    Sticker {
+        file_id: String,
+    },
+    /// Stripped of metadata when published
+    Document {
+        file_id: String,
+        extension: String,
+        caption: Option<String>,
+    },
+}
+
+impl OutgoingContent {
+    pub fn from_message(message: &Message<'_>) -> Vec<Self> {
+        let mut contents = vec![];
+        let caption = message.caption.as_deref().map(str::to_string);
+
+        if let Some(text) = message.text.as_deref() {
+            contents.push(Self::Text {
+                text: text.to_string(),
+            });
+        }
+        if let Some(photo_size) = message.photo.as_ref().and_then(|sizes| sizes.first()) {
+            contents.push(Self::Photo {
+                file_id: photo_size.file_id.to_string(),
+                caption: caption.clone(),
+            });
+        }
+        if let Some(animation) = message.animation.as_ref() {
+            contents.push(Self::Animation {
+                file_id: animation.file_id.to_string(),
+                duration: animation.duration,
+                width: animation.width,
+                height: animation.height,
+                caption,
+            });
+        }
+        if let Some(sticker) = message.sticker.as_ref() {
+            contents.push(Self::Sticker {
+                file_id: sticker.file_id.to_string(),
+            });
+        }
+        if message.animation.is_none()
+            && let Some(document) = message.document.as_ref()
+            && let Some(extension) = document
+                .file_name
+                .as_deref()
+                .and_then(sanitize::supported_extension)
+        {
+            contents.push(Self::Document {
+                file_id: document.file_id.to_string(),
+                extension,
+                caption: message.caption.as_deref().map(str::to_string),
+            });
+        }
+
+        contents
+    }
+}
+
+impl Outbox {
+    pub async fn open(file: &Path) -> anyhow::Result<Self> {
+        let messages: Vec<OutgoingMessage> = match file.exists() {
+            true => {
+                let contents = tokio::fs::read(file).await?;
+
+                serde_json::from_slice(&contents)?
+            }
+            false => vec![],
+        };
+        OUTBOX_MESSAGES.set(messages.len() as i64);
+
+        Ok(Self(Mutex::new(messages)))
+    }
+
+    pub async fn push(&self, message: OutgoingMessage) {
+        let mut messages = self.0.lock().await;
+        messages.push(message);
+        OUTBOX_MESSAGES.set(messages.len() as i64);
+    }
+
+    /// Messages to release by `now`, marked as being published, so their authors can't change
+    /// them anymore. They stay in the outbox until removed, so a crash while publishing doesn't
+    /// lose them.
+    pub async fn due(&self, now: i64) -> Vec<OutgoingMessage> {
+        self.0
+            .lock()
+            .await
+            .iter_mut()
+            .filter(|message| !message.publishing && message.release_at <= now)
+            .map(|message| {
+                message.publishing = true;
+                message.clone()
+            })
+            .collect()
+    }
+
+    /// Puts off publishing the message after a failed attempt.
+    pub async fn postpone(&self, id: u64, release_at: i64) {
+        let mut messages = self.0.lock().await;
+        if let Some(message) = messages.iter_mut().find(|message| message.id == id) {
+            message.release_at = release_at;
+            message.attempts += 1;
+            message.publishing = false;
+        }
+    }
+
+    pub async fn remove(&self, id: u64) {
+        let mut messages = self.0.lock().await;
+        messages.retain(|message| message.id != id);
+        OUTBOX_MESSAGES.set(messages.len() as i64);
+    }
+
+    /// Replaces contents of the author's message with their edited version, unless it is being
+    /// published already.
+    pub async fn edit(
+        &self,
+        author_chat_id: i64,
+        source_message_id: i32,
+        contents: Vec<OutgoingContent>,
+    ) -> HeldBack {
+        let mut messages = self.0.lock().await;
+        let message = messages.iter_mut().find(|message| {
+            message.author_chat_id == author_chat_id
+                && message.source_message_id == source_message_id
+        });
+
+        match message {
+            Some(message) if message.publishing => HeldBack::Publishing,
+            Some(message) => {
+                message.contents = contents;
+                HeldBack::Waiting
+            }
+            None => HeldBack::No,
+        }
+    }
+
+    /// Withdraws the author's message, unless it is being published already.
+    pub async fn withdraw(&self, author_chat_id: i64, source_message_id: i32) -> HeldBack {
+        let mut messages = self.0.lock().await;
+        let position = messages.iter().position(|message| {
+            message.author_chat_id == author_chat_id
+                && message.source_message_id == source_message_id
+        });
+
+        match position {
+            Some(position) if messages[position].publishing => HeldBack::Publishing,
+            Some(position) => {
+                messages.remove(position);
+                OUTBOX_MESSAGES.set(messages.len() as i64);
+                HeldBack::Waiting
+            }
+            None => HeldBack::No,
+        }
+    }
+
+    pub async fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let contents = { serde_json::to_vec(&*self.0.lock().await) }?;
+        tokio::fs::write(file, contents)
+            .await
+            .context("Failed to write outbox")?;
+
+        Ok(())
+    }
+}
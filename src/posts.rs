@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// Published anonymous messages their authors can still edit or delete. This is the only place
+/// linking authors to their posts, so posts are forgotten once the edit window is over.
+pub struct Posts(Mutex<Vec<Post>>);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Post {
+    pub author_id: i64,
+    /// Message in the author's private chat the post is a copy of
+    pub source_message_id: i32,
+    pub chat_id: i64,
+    pub parts: Vec<PostedPart>,
+    /// Unix timestamp
+    pub posted_at: i64,
+}
+
+/// Message the post was published as, one for every [`crate::outbox::OutgoingContent`].
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PostedPart {
+    pub message_id: i32,
+    pub kind: PartKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PartKind {
+    Text,
+    /// Photo, animation or document, their caption can be edited or added later
+    Media,
+    /// Sticker, which can't be edited
+    Other,
+}
+
+impl Posts {
+    pub async fn open(file: &Path) -> anyhow::Result<Self> {
+        let posts = match file.exists() {
+            true => {
+                let contents = tokio::fs::read(file).await?;
+
+                serde_json::from_slice(&contents)?
+            }
+            false => vec![],
+        };
+
+        Ok(Self(Mutex::new(posts)))
+    }
+
+    /// Remembers the post. Parts of a post published in several attempts are added to the parts
+    /// posted before.
+    pub async fn add(&self, post: Post) {
+        let mut posts = self.0.lock().await;
+        let existing = posts.iter_mut().find(|existing| {
+            existing.author_id == post.author_id
+                && existing.source_message_id == post.source_message_id
+                && existing.chat_id == post.chat_id
+        });
+
+        match existing {
+            Some(existing) => existing.parts.extend(post.parts),
+            None => posts.push(post),
+        }
+    }
+
+    /// Forgets posts published before `oldest`. Returns whether any were.
+    pub async fn prune(&self, oldest: i64) -> bool {
+        let mut posts = self.0.lock().await;
+        let count = posts.len();
+        posts.retain(|post| post.posted_at >= oldest);
+
+        posts.len() != count
+    }
+
+    /// Post of the author made from the message, unless it was published before `oldest`.
+    pub async fn find(&self, author_id: i64, source_message_id: i32, oldest: i64) -> Option<Post> {
+        self.0
+            .lock()
+            .await
+            .iter()
+            .find(|post| {
+                post.author_id == author_id
+                    && post.source_message_id == source_message_id
+                    && post.posted_at >= oldest
+            })
+            .cloned()
+    }
+
+    pub async fn remove(&self, author_id: i64, source_message_id: i32) {
+        self.0.lock().await.retain(|post| {
+            post.author_id != author_id || post.source_message_id != source_message_id
+        });
+    }
+
+    pub async fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let contents = { serde_json::to_vec(&*self.0.lock().await) }?;
+        tokio::fs::write(file, contents)
+            .await
+            .context("Failed to write posts")?;
+
+        Ok(())
+    }
+}
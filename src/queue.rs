@@ -0,0 +1,247 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::Context;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+
+use crate::{bot::entities::UpdateMessage, log::error, metrics::QUEUED_UPDATES};
+
+/// Acknowledged updates waiting for a worker. Every update is a file named by its update id, so
+/// updates survive restarts and are replayed in order.
+pub struct UpdateQueue {
+    dir: PathBuf,
+    capacity: usize,
+    len: AtomicUsize,
+    sender: UnboundedSender<QueuedUpdate>,
+    receiver: Mutex<Option<UnboundedReceiver<QueuedUpdate>>>,
+}
+
+/// Update handed to the workers. The sender comes along, so the update isn't parsed just to
+/// find its worker.
+pub struct QueuedUpdate {
+    pub update_id: i64,
+    /// See [`UpdateMessage::sender_id`]
+    pub sender_id: Option<i64>,
+}
+
+impl UpdateQueue {
+    pub async fn open(dir: &Path, capacity: usize) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create queue directory {}", dir.display()))?;
+
+        let mut update_ids = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(update_id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse::<i64>().ok())
+            {
+                update_ids.push(update_id);
+            }
+        }
+        update_ids.sort_unstable();
+
+        let (sender, receiver) = unbounded_channel();
+        let queue = Self {
+            dir: dir.to_path_buf(),
+            capacity,
+            len: AtomicUsize::new(update_ids.len()),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        };
+        // Updates left from the previous run were parsed by it, their senders are found anew
+        for &update_id in &update_ids {
+            let sender_id = match queue.load(update_id).await {
+                Ok(update) => serde_json::from_slice::<UpdateMessage>(&update)
+                    .ok()
+                    .and_then(|update| update.sender_id()),
+                Err(err) => {
+                    error!("Failed to load update {update_id}: {err:#}");
+                    None
+                }
+            };
+            queue.sender.send(QueuedUpdate {
+                update_id,
+                sender_id,
+            })?;
+        }
+        QUEUED_UPDATES.set(update_ids.len() as i64);
+
+        Ok(queue)
+    }
+
+    /// Stores the update and hands it to the workers. Fails when the queue is full, so telegram
+    /// retries the update later.
+    pub async fn push(
+        &self,
+        update_id: i64,
+        sender_id: Option<i64>,
+        update: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.len.load(Ordering::Relaxed) >= self.capacity {
+            anyhow::bail!("Update queue is full");
+        }
+
+        let file = self.update_file(update_id);
+        let tmp_file = file.with_extension("tmp");
+        let mut out = tokio::fs::File::create(&tmp_file).await?;
+        out.write_all(update).await?;
+        out.sync_all().await?;
+        tokio::fs::rename(&tmp_file, &file)
+            .await
+            .with_context(|| format!("Failed to store update {update_id}"))?;
+
+        QUEUED_UPDATES.set(self.len.fetch_add(1, Ordering::Relaxed) as i64 + 1);
+        self.sender.send(QueuedUpdate {
+            update_id,
+            sender_id,
+        })?;
+
+        Ok(())
+    }
+
+    /// Reads the update as it came from telegram.
+    pub async fn load(&self, update_id: i64) -> anyhow::Result<Vec<u8>> {
+        Ok(tokio::fs::read(self.update_file(update_id)).await?)
+    }
+
+    pub async fn remove(&self, update_id: i64) -> anyhow::Result<()> {
+        tokio::fs::remove_file(self.update_file(update_id))
+            .await
+            .with_context(|| format!("Failed to remove update {update_id} from queue"))?;
+        QUEUED_UPDATES.set(self.len.fetch_sub(1, Ordering::Relaxed) as i64 - 1);
+
+        Ok(())
+    }
+
+    /// Receiver of queued updates. There is only one, for the worker pool.
+    pub fn take_receiver(&self) -> Option<UnboundedReceiver<QueuedUpdate>> {
+        self.receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+
+    fn update_file(&self, update_id: i64) -> PathBuf {
+        self.dir.join(format!("{update_id}.json"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("anon-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn callback_update(update_id: i64, user_id: i64) -> String {
+        serde_json::json!({
+            "update_id": update_id,
+            "callback_query": {"id": "1", "from": {"id": user_id, "is_bot": false}},
+        })
+        .to_string()
+    }
+
+    fn drain(receiver: &mut UnboundedReceiver<QueuedUpdate>) -> Vec<(i64, Option<i64>)> {
+        let mut updates = vec![];
+        while let Ok(update) = receiver.try_recv() {
+            updates.push((update.update_id, update.sender_id));
+        }
+
+        updates
+    }
+
+    #[tokio::test]
+    async fn replays_leftover_updates_in_order() {
+        let dir = TempDir::new("queue-replay");
+        for update_id in [100, 9, 10, 2] {
+            std::fs::write(
+                dir.0.join(format!("{update_id}.json")),
+                callback_update(update_id, update_id * 1000),
+            )
+            .unwrap();
+        }
+        // A broken update is still replayed, the worker reports it
+        std::fs::write(dir.0.join("50.json"), "{").unwrap();
+        // Partly written updates and strangers are left alone
+        std::fs::write(dir.0.join("11.tmp"), callback_update(11, 1)).unwrap();
+        std::fs::write(dir.0.join("notes.json"), "{}").unwrap();
+        std::fs::write(dir.0.join("12"), callback_update(12, 1)).unwrap();
+
+        let queue = UpdateQueue::open(&dir.0, 10).await.unwrap();
+        let mut receiver = queue.take_receiver().unwrap();
+        assert!(queue.take_receiver().is_none());
+
+        assert_eq!(
+            drain(&mut receiver),
+            [
+                (2, Some(2000)),
+                (9, Some(9000)),
+                (10, Some(10000)),
+                (50, None),
+                (100, Some(100_000)),
+            ]
+        );
+        assert_eq!(queue.len.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn refuses_updates_past_capacity() {
+        let dir = TempDir::new("queue-capacity");
+        let queue = UpdateQueue::open(&dir.0, 2).await.unwrap();
+        let mut receiver = queue.take_receiver().unwrap();
+
+        for update_id in [1, 2] {
+            let update = callback_update(update_id, 7);
+            queue
+                .push(update_id, Some(7), update.as_bytes())
+                .await
+                .unwrap();
+        }
+        assert!(queue.push(3, Some(7), b"{}").await.is_err());
+        assert!(!dir.0.join("3.json").exists());
+
+        queue.remove(1).await.unwrap();
+        queue.push(3, Some(7), b"{}").await.unwrap();
+        assert_eq!(
+            drain(&mut receiver),
+            [(1, Some(7)), (2, Some(7)), (3, Some(7))]
+        );
+        assert_eq!(
+            queue.load(2).await.unwrap(),
+            callback_update(2, 7).as_bytes()
+        );
+
+        // The next run picks up where this one stopped
+        drop(queue);
+        let queue = UpdateQueue::open(&dir.0, 2).await.unwrap();
+        let mut receiver = queue.take_receiver().unwrap();
+        assert_eq!(drain(&mut receiver), [(2, Some(7)), (3, None)]);
+    }
+}
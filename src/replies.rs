@@ -0,0 +1,38 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use tokio::sync::oneshot;
+
+use crate::bot::entities::WebhookResponse;
+
+/// Webhook requests waiting for the worker to come up with a reply. A reply handed over here is
+/// sent back in the webhook response body, which saves a separate api call.
+#[derive(Default)]
+pub struct PendingReplies(Mutex<HashMap<i64, oneshot::Sender<WebhookResponse>>>);
+
+impl PendingReplies {
+    pub fn register(&self, update_id: i64) -> oneshot::Receiver<WebhookResponse> {
+        let (sender, receiver) = oneshot::channel();
+        self.lock().insert(update_id, sender);
+
+        receiver
+    }
+
+    pub fn remove(&self, update_id: i64) {
+        self.lock().remove(&update_id);
+    }
+
+    /// Hands the reply to the waiting webhook request. Gives it back when nobody waits anymore.
+    pub fn deliver(&self, update_id: i64, reply: WebhookResponse) -> Result<(), WebhookResponse> {
+        match self.lock().remove(&update_id) {
+            Some(sender) => sender.send(reply),
+            None => Err(reply),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i64, oneshot::Sender<WebhookResponse>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,250 @@
+use std::ops::Range;
+
+use anyhow::Context;
+
+use super::{read_u16_be, read_u32_be};
+
+/// Brands of HEIF images, the format of HEIC photos.
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"mif1", b"msf1",
+];
+const XMP_CONTENT_TYPE: &[u8] = b"application/rdf+xml";
+
+/// Overwrites EXIF and XMP items with zeros. Items are referenced by offsets all over the file,
+/// so they are blanked in place rather than removed.
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let boxes = read_boxes(data, 0..data.len())?;
+    let ftyp = find_box(&boxes, b"ftyp").context("HEIF has no ftyp box")?;
+    let is_heif = data[ftyp.payload.clone()]
+        .chunks_exact(4)
+        .any(|brand| HEIF_BRANDS.iter().any(|heif_brand| brand == *heif_brand));
+    if !is_heif {
+        anyhow::bail!("Not a HEIF image");
+    }
+
+    let meta = find_box(&boxes, b"meta").context("HEIF has no meta box")?;
+    // Full box: version and flags go first
+    let meta_children = read_boxes(data, meta.payload.start + 4..meta.payload.end)?;
+    let iinf = find_box(&meta_children, b"iinf").context("HEIF has no iinf box")?;
+    let iloc = find_box(&meta_children, b"iloc").context("HEIF has no iloc box")?;
+    let idat = find_box(&meta_children, b"idat").map(|idat| idat.payload.clone());
+
+    let metadata_items = metadata_items(data, iinf.payload.clone())?;
+    let mut out = data.to_vec();
+    for extent in item_extents(data, iloc.payload.clone(), &metadata_items, idat)? {
+        out.get_mut(extent)
+            .context("HEIF item is out of bounds")?
+            .fill(0);
+    }
+
+    Ok(out)
+}
+
+struct IsoBox {
+    box_type: [u8; 4],
+    payload: Range<usize>,
+}
+
+fn read_boxes(data: &[u8], range: Range<usize>) -> anyhow::Result<Vec<IsoBox>> {
+    let mut boxes = vec![];
+    let mut pos = range.start;
+    while pos < range.end {
+        let size = read_u32_be(data, pos)? as usize;
+        let box_type = data
+            .get(pos + 4..pos + 8)
+            .context("Unexpected end of HEIF")?
+            .try_into()?;
+        let (header_length, size) = match size {
+            0 => (8, range.end - pos),
+            1 => (16, read_u64_be(data, pos + 8)? as usize),
+            size => (8, size),
+        };
+        let end = pos
+            .checked_add(size)
+            .filter(|&end| end <= range.end && size >= header_length)
+            .context("HEIF box is out of bounds")?;
+
+        boxes.push(IsoBox {
+            box_type,
+            payload: pos + header_length..end,
+        });
+        pos = end;
+    }
+
+    Ok(boxes)
+}
+
+fn find_box<'a>(boxes: &'a [IsoBox], box_type: &[u8; 4]) -> Option<&'a IsoBox> {
+    boxes.iter().find(|iso_box| &iso_box.box_type == box_type)
+}
+
+/// Ids of EXIF and XMP items listed in `iinf`.
+fn metadata_items(data: &[u8], iinf: Range<usize>) -> anyhow::Result<Vec<u32>> {
+    let version = *data.get(iinf.start).context("Unexpected end of HEIF")?;
+    let entries_start = iinf.start + 4 + if version == 0 { 2 } else { 4 };
+
+    let mut items = vec![];
+    for infe in read_boxes(data, entries_start..iinf.end)? {
+        if &infe.box_type != b"infe" {
+            continue;
+        }
+        let pos = infe.payload.start;
+        let version = *data.get(pos).context("Unexpected end of HEIF")?;
+        // Older entries have no item types, they can't hold EXIF
+        let (item_id, type_pos) = match version {
+            2 => (read_u16_be(data, pos + 4)? as u32, pos + 8),
+            3 => (read_u32_be(data, pos + 4)?, pos + 10),
+            _ => continue,
+        };
+        let item_type = data
+            .get(type_pos..type_pos + 4)
+            .context("Unexpected end of HEIF")?;
+
+        let is_metadata = match item_type {
+            b"Exif" => true,
+            b"mime" => data
+                .get(type_pos + 4..infe.payload.end)
+                .context("HEIF item info is out of bounds")?
+                .split(|&byte| byte == 0)
+                .nth(1)
+                .is_some_and(|content_type| content_type == XMP_CONTENT_TYPE),
+            _ => false,
+        };
+        if is_metadata {
+            items.push(item_id);
+        }
+    }
+
+    Ok(items)
+}
+
+/// File ranges of the items, read from `iloc`.
+fn item_extents(
+    data: &[u8],
+    iloc: Range<usize>,
+    items: &[u32],
+    idat: Option<Range<usize>>,
+) -> anyhow::Result<Vec<Range<usize>>> {
+    let mut reader = Reader {
+        data,
+        pos: iloc.start,
+    };
+    let version = reader.read(1)?;
+    reader.read(3)?;
+    let sizes = reader.read(1)? as u8;
+    let (offset_size, length_size) = (sizes >> 4, sizes & 0x0f);
+    let sizes = reader.read(1)? as u8;
+    let base_offset_size = sizes >> 4;
+    let index_size = if version >= 1 { sizes & 0x0f } else { 0 };
+    let item_count = reader.read(if version < 2 { 2 } else { 4 })?;
+
+    let mut extents = vec![];
+    for _ in 0..item_count {
+        let item_id = reader.read(if version < 2 { 2 } else { 4 })? as u32;
+        let construction_method = match version {
+            0 => 0,
+            _ => reader.read(2)? & 0x0f,
+        };
+        reader.read(2)?;
+        let base_offset = reader.read(base_offset_size)?;
+        let extent_count = reader.read(2)?;
+
+        for _ in 0..extent_count {
+            reader.read(index_size)?;
+            let offset = base_offset
+                .checked_add(reader.read(offset_size)?)
+                .context("HEIF item offset overflows")?;
+            let length = reader.read(length_size)?;
+            if !items.contains(&item_id) {
+                continue;
+            }
+
+            let start = match construction_method {
+                0 => offset as usize,
+                1 => idat
+                    .as_ref()
+                    .context("HEIF has no idat box")?
+                    .start
+                    .checked_add(offset as usize)
+                    .context("HEIF item offset overflows")?,
+                _ => anyhow::bail!("Unsupported HEIF item construction method"),
+            };
+            if length == 0 {
+                anyhow::bail!("HEIF item {item_id} has no length");
+            }
+            let end = start
+                .checked_add(length as usize)
+                .context("HEIF item length overflows")?;
+            extents.push(start..end);
+        }
+    }
+
+    Ok(extents)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    /// Reads a big endian number of `size` bytes.
+    fn read(&mut self, size: u8) -> anyhow::Result<u64> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + size as usize)
+            .context("Unexpected end of HEIF")?;
+        self.pos += size as usize;
+
+        Ok(bytes
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+    }
+}
+
+fn read_u64_be(data: &[u8], pos: usize) -> anyhow::Result<u64> {
+    let bytes = data.get(pos..pos + 8).context("Unexpected end of HEIF")?;
+
+    Ok(u64::from_be_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::contains;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/exif.heic");
+
+    #[test]
+    fn blanks_exif_and_xmp() {
+        let stripped = strip(FIXTURE).unwrap();
+
+        assert_eq!(stripped.len(), FIXTURE.len());
+        assert!(contains(&stripped, b"HEVC-IMAGE-DATA"));
+        assert!(!contains(&stripped, b"Camera-SN-1234"));
+        assert!(!contains(&stripped, b"Ivan Petrov"));
+    }
+
+    #[test]
+    fn refuses_other_brands() {
+        let mut data = FIXTURE.to_vec();
+        data[8..12].copy_from_slice(b"avif");
+        data[16..24].copy_from_slice(b"avifavif");
+
+        assert!(strip(&data).is_err());
+    }
+
+    #[test]
+    fn refuses_item_info_shorter_than_its_header() {
+        let data = include_bytes!("fixtures/short_infe.heic");
+
+        assert!(strip(data).is_err());
+    }
+
+    #[test]
+    fn refuses_overflowing_offsets() {
+        let data = include_bytes!("fixtures/offset_overflow.heic");
+
+        assert!(strip(data).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::Context;
+
+use super::read_u16_be;
+
+const SOS: u8 = 0xda;
+const EOI: u8 = 0xd9;
+const APP0: u8 = 0xe0;
+const APP1: u8 = 0xe1;
+const APP2: u8 = 0xe2;
+const APP14: u8 = 0xee;
+const COM: u8 = 0xfe;
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Drops EXIF, XMP and other application segments, comments and anything after the image.
+/// JFIF, ICC profiles and Adobe color transforms are kept as they change how the image looks,
+/// and so is the EXIF orientation.
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut orientation = None;
+    let mut pos = 2;
+
+    loop {
+        if data.get(pos) != Some(&0xff) {
+            anyhow::bail!("Expected JPEG marker at {pos}");
+        }
+        while data.get(pos) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = *data.get(pos).context("Unexpected end of JPEG")?;
+        pos += 1;
+
+        match marker {
+            EOI => {
+                out.extend_from_slice(&[0xff, EOI]);
+                break;
+            }
+            0x01 | 0xd0..=0xd7 => out.extend_from_slice(&[0xff, marker]),
+            _ => {
+                let length = read_u16_be(data, pos)? as usize;
+                if length < 2 {
+                    anyhow::bail!("Invalid JPEG segment length at {pos}");
+                }
+                let segment = data
+                    .get(pos..pos + length)
+                    .context("JPEG segment is out of bounds")?;
+                pos += length;
+
+                let keep = match marker {
+                    APP0 => true,
+                    APP1 => {
+                        orientation = orientation.or_else(|| exif_orientation(&segment[2..]));
+                        false
+                    }
+                    APP2 => segment[2..].starts_with(b"ICC_PROFILE\0"),
+                    APP14 => segment[2..].starts_with(b"Adobe"),
+                    0xe3..=0xef | COM => false,
+                    _ => true,
+                };
+                if keep {
+                    // The orientation goes right before the first frame or table segment
+                    if marker != APP0
+                        && let Some(orientation) = orientation.take()
+                    {
+                        out.extend_from_slice(&orientation_segment(orientation));
+                    }
+                    out.extend_from_slice(&[0xff, marker]);
+                    out.extend_from_slice(segment);
+                }
+
+                if marker == SOS {
+                    let scan_end = scan_end(data, pos);
+                    out.extend_from_slice(&data[pos..scan_end]);
+                    pos = scan_end;
+                }
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Finds the end of entropy-coded data: the first marker which is not a stuffed byte or a restart.
+fn scan_end(data: &[u8], mut pos: usize) -> usize {
+    while pos + 1 < data.len() {
+        if data[pos] == 0xff && !matches!(data[pos + 1], 0x00 | 0xd0..=0xd7 | 0xff) {
+            return pos;
+        }
+        pos += 1;
+    }
+
+    data.len()
+}
+
+/// Reads the orientation from IFD0 of an EXIF segment.
+fn exif_orientation(segment: &[u8]) -> Option<u16> {
+    let tiff = segment.strip_prefix(b"Exif\0\0")?;
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(match little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let read_u32 = |pos: usize| {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// Minimal EXIF segment with nothing but the orientation.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    // SHORT, one value, padded to four bytes
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // No next IFD
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+
+    let mut segment = vec![0xff, APP1];
+    segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::contains;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/exif.jpg");
+
+    /// Markers of the segments in the file, in order.
+    fn markers(data: &[u8]) -> Vec<u8> {
+        let mut markers = vec![];
+        let mut pos = 2;
+        while data[pos + 1] != EOI {
+            let marker = data[pos + 1];
+            markers.push(marker);
+            pos += 2 + read_u16_be(data, pos + 2).unwrap() as usize;
+            if marker == SOS {
+                pos = scan_end(data, pos);
+            }
+        }
+
+        markers
+    }
+
+    #[test]
+    fn drops_metadata() {
+        let stripped = strip(FIXTURE).unwrap();
+
+        assert!(!contains(&stripped, b"Camera-SN-1234"));
+        assert!(!contains(&stripped, b"http://ns.adobe.com/xap/1.0/"));
+        assert!(!contains(&stripped, b"Ivan Petrov"));
+        assert!(stripped.ends_with(&[0xff, EOI]));
+        assert_eq!(
+            markers(&stripped),
+            [APP0, APP1, APP2, 0xdb, 0xc9, 0xcc, SOS]
+        );
+    }
+
+    #[test]
+    fn keeps_orientation() {
+        let stripped = strip(FIXTURE).unwrap();
+        let segment = orientation_segment(6);
+
+        assert!(contains(&stripped, &segment));
+        assert_eq!(exif_orientation(&segment[4..]), Some(6));
+    }
+
+    #[test]
+    fn keeps_image_data() {
+        let stripped = strip(FIXTURE).unwrap();
+
+        assert!(contains(&stripped, &[0xd2, 0xcf, 0x20, 0xff, EOI]));
+    }
+
+    #[test]
+    fn refuses_invalid_segments() {
+        let mut data = FIXTURE.to_vec();
+        // Length of the JFIF segment
+        data[4..6].copy_from_slice(&[0, 1]);
+
+        assert!(strip(&data).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Removes metadata which could point at the author from files sent as documents: EXIF with GPS
+//! and camera serials, XMP, document properties with names and so on.
+
+mod heif;
+mod jpeg;
+mod office;
+mod pdf;
+mod png;
+mod webp;
+
+/// Extensions of files which can be sanitized. Anything else is refused up front.
+const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "webp", "heic", "heif", "pdf", "docx", "xlsx", "pptx", "odt", "ods",
+    "odp",
+];
+
+/// Lowercase extension of a file which can be sanitized.
+pub fn supported_extension(file_name: &str) -> Option<String> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+
+    SUPPORTED_EXTENSIONS
+        .contains(&extension.as_str())
+        .then_some(extension)
+}
+
+/// Returns the file without metadata. The format is detected by contents, files which don't
+/// look like any supported format are refused.
+pub fn sanitize(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    match data {
+        [0xff, 0xd8, 0xff, ..] => jpeg::strip(data),
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => png::strip(data),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => webp::strip(data),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => heif::strip(data),
+        [b'%', b'P', b'D', b'F', b'-', ..] => pdf::strip(data),
+        [b'P', b'K', 0x03, 0x04, ..] => office::strip(data),
+        _ => anyhow::bail!("Unsupported file format"),
+    }
+}
+
+fn read_u16_be(data: &[u8], pos: usize) -> anyhow::Result<u16> {
+    let bytes = data
+        .get(pos..pos + 2)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of file"))?;
+
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32_be(data: &[u8], pos: usize) -> anyhow::Result<u32> {
+    let bytes = data
+        .get(pos..pos + 4)
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of file"))?;
+
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    /// Files with metadata which sanitize fine.
+    const FIXTURES: &[(&str, &[u8])] = &[
+        ("exif.jpg", include_bytes!("fixtures/exif.jpg")),
+        ("text.png", include_bytes!("fixtures/text.png")),
+        ("exif.webp", include_bytes!("fixtures/exif.webp")),
+        ("exif.heic", include_bytes!("fixtures/exif.heic")),
+        ("metadata.pdf", include_bytes!("fixtures/metadata.pdf")),
+        ("document.docx", include_bytes!("fixtures/document.docx")),
+        ("document.odt", include_bytes!("fixtures/document.odt")),
+    ];
+
+    #[test]
+    fn sanitizes_fixtures() {
+        for (name, data) in FIXTURES {
+            let sanitized = sanitize(data).unwrap_or_else(|err| panic!("{name}: {err:#}"));
+            assert!(!contains(&sanitized, b"Ivan Petrov"), "{name}");
+            assert!(!contains(&sanitized, b"Camera-SN-1234"), "{name}");
+        }
+    }
+
+    #[test]
+    fn supports_extensions_in_any_case() {
+        assert_eq!(supported_extension("photo.JPG").as_deref(), Some("jpg"));
+        assert_eq!(supported_extension("a.b.docx").as_deref(), Some("docx"));
+        assert_eq!(supported_extension("archive.zip"), None);
+        assert_eq!(supported_extension("docx"), None);
+    }
+
+    #[test]
+    fn refuses_unknown_formats() {
+        for data in [
+            b"".as_slice(),
+            b"GIF89a",
+            b"plain text",
+            b"PK\x03\x04",
+            b"%PDF-",
+            b"\xff\xd8\xff",
+        ] {
+            assert!(sanitize(data).is_err(), "{data:?}");
+        }
+    }
+
+    /// Only cutting the data after the end of an image goes unnoticed, it is dropped anyway.
+    #[test]
+    fn refuses_truncated_files() {
+        for (name, data) in FIXTURES {
+            let sanitized = sanitize(data).unwrap();
+            for length in 0..data.len() {
+                if let Ok(truncated) = sanitize(&data[..length]) {
+                    assert_eq!(truncated, sanitized, "{name} cut at {length}");
+                }
+            }
+        }
+    }
+
+    /// Corrupted files may still sanitize, what matters is that they don't panic.
+    #[test]
+    fn survives_corrupted_files() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for (_, data) in FIXTURES {
+            for _ in 0..500 {
+                let mut data = data.to_vec();
+                for _ in 0..rng.random_range(1..8) {
+                    let position = rng.random_range(0..data.len());
+                    data[position] = rng.random();
+                }
+                let _ = sanitize(&data);
+            }
+        }
+    }
+}
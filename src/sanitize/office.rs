@@ -0,0 +1,366 @@
+use std::io::{Cursor, Read, Write};
+
+use anyhow::Context;
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+const CORE_PROPERTIES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcterms="http://purl.org/dc/terms/" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"/>"#;
+const APP_PROPERTIES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Properties xmlns="http://schemas.openxmlformats.org/officeDocument/2006/extended-properties"/>"#;
+const CUSTOM_PROPERTIES: &str = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+<Properties xmlns="http://schemas.openxmlformats.org/officeDocument/2006/custom-properties"/>"#;
+const ODF_META: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-meta xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" office:version="1.2"/>"#;
+
+const ODF_SETTINGS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-settings xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" office:version="1.2"/>"#;
+
+/// Parts of OOXML documents known to have no names in them, `*` stands for any part of a file
+/// name. Comments, their authors, printer settings, embedded files and anything else unknown
+/// are refused.
+const OOXML_PARTS: &[&str] = &[
+    "[Content_Types].xml",
+    "_rels/.rels",
+    "docProps/core.xml",
+    "docProps/app.xml",
+    "docProps/custom.xml",
+    "word/document.xml",
+    "word/styles.xml",
+    "word/stylesWithEffects.xml",
+    "word/settings.xml",
+    "word/webSettings.xml",
+    "word/fontTable.xml",
+    "word/numbering.xml",
+    "word/footnotes.xml",
+    "word/endnotes.xml",
+    "word/header*.xml",
+    "word/footer*.xml",
+    "word/theme/theme*.xml",
+    "word/_rels/document.xml.rels",
+    "word/_rels/header*.xml.rels",
+    "word/_rels/footer*.xml.rels",
+    "word/_rels/footnotes.xml.rels",
+    "word/_rels/endnotes.xml.rels",
+    "xl/workbook.xml",
+    "xl/styles.xml",
+    "xl/sharedStrings.xml",
+    "xl/calcChain.xml",
+    "xl/worksheets/sheet*.xml",
+    "xl/worksheets/_rels/sheet*.xml.rels",
+    "xl/theme/theme*.xml",
+    "xl/tables/table*.xml",
+    "xl/drawings/drawing*.xml",
+    "xl/drawings/_rels/drawing*.xml.rels",
+    "xl/_rels/workbook.xml.rels",
+    "ppt/presentation.xml",
+    "ppt/presProps.xml",
+    "ppt/viewProps.xml",
+    "ppt/tableStyles.xml",
+    "ppt/slides/slide*.xml",
+    "ppt/slides/_rels/slide*.xml.rels",
+    "ppt/slideLayouts/slideLayout*.xml",
+    "ppt/slideLayouts/_rels/slideLayout*.xml.rels",
+    "ppt/slideMasters/slideMaster*.xml",
+    "ppt/slideMasters/_rels/slideMaster*.xml.rels",
+    "ppt/notesSlides/notesSlide*.xml",
+    "ppt/notesSlides/_rels/notesSlide*.xml.rels",
+    "ppt/notesMasters/notesMaster*.xml",
+    "ppt/notesMasters/_rels/notesMaster*.xml.rels",
+    "ppt/handoutMasters/handoutMaster*.xml",
+    "ppt/handoutMasters/_rels/handoutMaster*.xml.rels",
+    "ppt/theme/theme*.xml",
+    "ppt/theme/_rels/theme*.xml.rels",
+    "ppt/_rels/presentation.xml.rels",
+    "word/charts/chart*.xml",
+    "word/charts/style*.xml",
+    "word/charts/colors*.xml",
+    "word/charts/_rels/chart*.xml.rels",
+    "xl/charts/chart*.xml",
+    "xl/charts/style*.xml",
+    "xl/charts/colors*.xml",
+    "xl/charts/_rels/chart*.xml.rels",
+    "ppt/charts/chart*.xml",
+    "ppt/charts/style*.xml",
+    "ppt/charts/colors*.xml",
+    "ppt/charts/_rels/chart*.xml.rels",
+];
+/// Directories of OOXML documents with images, which are sanitized like the ones sent as is.
+const OOXML_IMAGE_DIRECTORIES: &[&str] = &["word/media/", "xl/media/", "ppt/media/", "docProps/"];
+/// Parts of ODF documents known to have no names in them.
+const ODF_PARTS: &[&str] = &[
+    "mimetype",
+    "META-INF/manifest.xml",
+    "manifest.rdf",
+    "content.xml",
+    "styles.xml",
+    "settings.xml",
+    "meta.xml",
+    "layout-cache",
+    "Configurations2/accelerator/current.xml",
+];
+const ODF_IMAGE_DIRECTORIES: &[&str] = &["Pictures/", "Thumbnails/"];
+/// Attributes and elements naming authors of comments and tracked changes, and links to local
+/// files, whose paths usually have the user name in them.
+const AUTHOR_MARKERS: &[&str] = &["w:author=", "<dc:creator>", "Target=\"file:"];
+/// Limit of the unpacked size of all parts, so a zip bomb can't take up all memory.
+const MAX_UNPACKED_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(PartialEq)]
+enum Kind {
+    /// docx, xlsx and pptx
+    OfficeOpenXml,
+    /// odt, ods and odp
+    OpenDocument,
+}
+
+/// Replaces document properties and settings with empty ones and strips images inside the document.
+/// Only parts known to be safe are kept, documents with anything else, such as comments or tracked
+/// changes naming their authors, are refused. Entries are written with a fixed time, so the archive
+/// doesn't tell when and in which time zone it was made.
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).context("Failed to read archive")?;
+    let kind = if archive.index_for_name("[Content_Types].xml").is_some() {
+        Kind::OfficeOpenXml
+    } else if archive.index_for_name("mimetype").is_some() {
+        Kind::OpenDocument
+    } else {
+        anyhow::bail!("Archive is not an office document");
+    };
+
+    let mut writer = ZipWriter::new(Cursor::new(vec![]));
+    let mut unpacked_size = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let name = entry.name().to_string();
+        let options = SimpleFileOptions::DEFAULT.compression_method(match entry.compression() {
+            CompressionMethod::Stored => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        });
+        if entry.is_dir() {
+            writer.add_directory(name, options)?;
+            continue;
+        }
+        if !is_safe_part(&kind, &name) {
+            anyhow::bail!("Document has part {name} which can't be sanitized");
+        }
+
+        let mut contents = vec![];
+        // Sizes in the archive can't be trusted, so only as much as is left of the limit is read
+        let left = MAX_UNPACKED_SIZE - unpacked_size;
+        (&mut entry).take(left + 1).read_to_end(&mut contents)?;
+        unpacked_size += contents.len() as u64;
+        if unpacked_size > MAX_UNPACKED_SIZE {
+            anyhow::bail!("Document is larger than {MAX_UNPACKED_SIZE} bytes unpacked");
+        }
+        let contents = match (&kind, name.as_str()) {
+            (Kind::OfficeOpenXml, "docProps/core.xml") => CORE_PROPERTIES.as_bytes().to_vec(),
+            (Kind::OfficeOpenXml, "docProps/app.xml") => APP_PROPERTIES.as_bytes().to_vec(),
+            (Kind::OfficeOpenXml, "docProps/custom.xml") => CUSTOM_PROPERTIES.as_bytes().to_vec(),
+            (Kind::OfficeOpenXml, "xl/workbook.xml") => {
+                let text = String::from_utf8(contents).context("Workbook is not UTF-8")?;
+
+                blank_abs_path(&text).into_bytes()
+            }
+            (Kind::OpenDocument, "meta.xml") => ODF_META.as_bytes().to_vec(),
+            (Kind::OpenDocument, "settings.xml") => ODF_SETTINGS.as_bytes().to_vec(),
+            (_, name) if name.ends_with(".xml") || name.ends_with(".rels") => {
+                let text = String::from_utf8_lossy(&contents);
+                if AUTHOR_MARKERS.iter().any(|marker| text.contains(marker)) {
+                    anyhow::bail!("Document names people or local files in {name}");
+                }
+                contents
+            }
+            (_, name) if is_image(name) => super::sanitize(&contents)
+                .with_context(|| format!("Failed to sanitize image {name}"))?,
+            _ => contents,
+        };
+
+        writer.start_file(name, options)?;
+        writer.write_all(&contents)?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
+
+fn is_image(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+
+    [".jpg", ".jpeg", ".png", ".webp"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+fn is_safe_part(kind: &Kind, name: &str) -> bool {
+    let (parts, image_directories) = match kind {
+        Kind::OfficeOpenXml => (OOXML_PARTS, OOXML_IMAGE_DIRECTORIES),
+        Kind::OpenDocument => (ODF_PARTS, ODF_IMAGE_DIRECTORIES),
+    };
+
+    parts.iter().any(|pattern| matches_pattern(pattern, name))
+        || (is_image(name)
+            && image_directories
+                .iter()
+                .any(|directory| name.starts_with(directory)))
+}
+
+/// Whether the name matches the pattern, whose `*` stands for anything but `/`.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|middle| !middle.contains('/')),
+        None => pattern == name,
+    }
+}
+
+/// Blanks the path the workbook was last saved to, which usually has the user name in it.
+fn blank_abs_path(workbook: &str) -> String {
+    const ELEMENT: &str = "<x15ac:absPath ";
+    const ATTRIBUTE: &str = "url=\"";
+
+    let mut result = String::with_capacity(workbook.len());
+    let mut rest = workbook;
+    while let Some(start) = rest.find(ELEMENT) {
+        let (before, element) = rest.split_at(start);
+        result.push_str(before);
+        let element_end = element.find('>').unwrap_or(element.len());
+        let url = element[..element_end]
+            .find(ATTRIBUTE)
+            .map(|position| position + ATTRIBUTE.len())
+            .and_then(|value_start| {
+                let value_end = value_start + element[value_start..].find('"')?;
+                Some((value_start, value_end))
+            });
+        match url {
+            Some((value_start, value_end)) => {
+                result.push_str(&element[..value_start]);
+                rest = &element[value_end..];
+            }
+            None => {
+                result.push_str(&element[..element_end]);
+                rest = &element[element_end..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::contains;
+
+    /// Contents of every file in the archive by name.
+    fn unpack(data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut archive = ZipArchive::new(Cursor::new(data)).unwrap();
+
+        (0..archive.len())
+            .map(|index| {
+                let mut entry = archive.by_index(index).unwrap();
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+
+                (entry.name().to_string(), contents)
+            })
+            .collect()
+    }
+
+    fn part<'a>(parts: &'a [(String, Vec<u8>)], name: &str) -> &'a [u8] {
+        &parts.iter().find(|(part, _)| part == name).unwrap().1
+    }
+
+    #[test]
+    fn replaces_ooxml_properties() {
+        let stripped = strip(include_bytes!("fixtures/document.docx")).unwrap();
+        let parts = unpack(&stripped);
+
+        assert_eq!(
+            part(&parts, "docProps/core.xml"),
+            CORE_PROPERTIES.as_bytes()
+        );
+        assert_eq!(part(&parts, "docProps/app.xml"), APP_PROPERTIES.as_bytes());
+        assert!(contains(part(&parts, "word/document.xml"), b"Hello"));
+        assert!(!contains(
+            part(&parts, "word/media/image1.jpg"),
+            b"Camera-SN-1234"
+        ));
+        for (name, contents) in &parts {
+            assert!(!contains(contents, b"Ivan Petrov"), "{name}");
+        }
+    }
+
+    #[test]
+    fn replaces_odf_meta_and_settings() {
+        let stripped = strip(include_bytes!("fixtures/document.odt")).unwrap();
+        let parts = unpack(&stripped);
+
+        assert_eq!(parts[0].0, "mimetype");
+        assert_eq!(part(&parts, "meta.xml"), ODF_META.as_bytes());
+        assert_eq!(part(&parts, "settings.xml"), ODF_SETTINGS.as_bytes());
+        assert!(!contains(part(&parts, "Pictures/image1.png"), b"tEXt"));
+        for (name, contents) in &parts {
+            assert!(!contains(contents, b"Ivan Petrov"), "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_parts() {
+        let err = strip(include_bytes!("fixtures/comments.docx")).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Document has part word/comments.xml which can't be sanitized"
+        );
+    }
+
+    #[test]
+    fn refuses_tracked_changes() {
+        let err = strip(include_bytes!("fixtures/tracked_changes.docx")).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Document names people or local files in word/document.xml"
+        );
+    }
+
+    #[test]
+    fn refuses_documents_too_large_unpacked() {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::DEFAULT;
+        writer.start_file("[Content_Types].xml", options).unwrap();
+        writer.start_file("word/document.xml", options).unwrap();
+        let chunk = vec![b' '; 1024 * 1024];
+        for _ in 0..=MAX_UNPACKED_SIZE / chunk.len() as u64 {
+            writer.write_all(&chunk).unwrap();
+        }
+        let data = writer.finish().unwrap().into_inner();
+
+        let err = strip(&data).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            format!("Document is larger than {MAX_UNPACKED_SIZE} bytes unpacked")
+        );
+    }
+
+    #[test]
+    fn blanks_saved_path() {
+        let workbook = r#"<workbook><x15ac:absPath url="C:\Users\ivan\" xmlns:x15ac="ns"/><sheets/></workbook>"#;
+
+        assert_eq!(
+            blank_abs_path(workbook),
+            r#"<workbook><x15ac:absPath url="" xmlns:x15ac="ns"/><sheets/></workbook>"#
+        );
+    }
+
+    #[test]
+    fn patterns_match_one_level() {
+        assert!(matches_pattern("word/header*.xml", "word/header1.xml"));
+        assert!(!matches_pattern("word/header*.xml", "word/header/1.xml"));
+        assert!(!matches_pattern("word/header*.xml", "word/footer1.xml"));
+        assert!(matches_pattern("mimetype", "mimetype"));
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::Context;
+use lopdf::{Dictionary, Document, Object};
+
+/// Keys with metadata: XMP streams and private data of the editing application.
+const METADATA_KEYS: &[&[u8]] = &[b"Metadata", b"PieceInfo"];
+
+/// Drops the document information dictionary, the file id and XMP metadata, and strips embedded
+/// JPEG images. Documents with comments are refused, as those keep names of their authors. The
+/// document is written anew, so earlier revisions left by incremental saves are gone too.
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut document = Document::load_mem(data).context("Failed to parse PDF")?;
+    if document.is_encrypted() {
+        anyhow::bail!("PDF is encrypted");
+    }
+
+    document.trailer.remove(b"Info");
+    document.trailer.remove(b"ID");
+    for object in document.objects.values_mut() {
+        check_annotations(object)?;
+        if let Object::Stream(stream) = object
+            && is_jpeg(&stream.dict)?
+        {
+            if !stream.content.starts_with(&[0xff, 0xd8, 0xff]) {
+                anyhow::bail!("PDF has a broken JPEG image");
+            }
+            let content = super::jpeg::strip(&stream.content).context("Failed to strip image")?;
+            stream.set_content(content);
+        }
+
+        let dictionary = match object {
+            Object::Dictionary(dictionary) => dictionary,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        for key in METADATA_KEYS {
+            dictionary.remove(key);
+        }
+    }
+    document.prune_objects();
+    document.renumber_objects();
+
+    let mut out = vec![];
+    document.save_to(&mut out).context("Failed to write PDF")?;
+
+    Ok(out)
+}
+
+/// Refuses annotations naming their authors, which comments and other markup have. Form fields
+/// have their names in the same key, so those are let through.
+fn check_annotations(object: &Object) -> anyhow::Result<()> {
+    match object {
+        Object::Dictionary(dictionary) => {
+            let is_annotation = dictionary.has(b"Subtype") && dictionary.has(b"Rect");
+            let is_widget = dictionary
+                .get(b"Subtype")
+                .and_then(Object::as_name)
+                .is_ok_and(|subtype| subtype == b"Widget");
+            if is_annotation && !is_widget && dictionary.has(b"T") {
+                anyhow::bail!("PDF has comments");
+            }
+            for (_, value) in dictionary.iter() {
+                check_annotations(value)?;
+            }
+        }
+        Object::Array(array) => {
+            for value in array {
+                check_annotations(value)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Whether the stream is a JPEG image, which may have EXIF with GPS in it. Images with other
+/// filters on top of JPEG are refused, as they can't be stripped.
+fn is_jpeg(dictionary: &Dictionary) -> anyhow::Result<bool> {
+    let filters = match dictionary.get(b"Filter") {
+        Ok(Object::Name(name)) => vec![name.as_slice()],
+        Ok(Object::Array(array)) => array
+            .iter()
+            .filter_map(|filter| filter.as_name().ok())
+            .collect(),
+        _ => return Ok(false),
+    };
+    match filters.as_slice() {
+        [b"DCTDecode"] => Ok(true),
+        filters if filters.contains(&b"DCTDecode".as_slice()) => {
+            anyhow::bail!("PDF has a JPEG image which can't be stripped")
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lopdf::ObjectId;
+
+    use super::*;
+    use crate::sanitize::contains;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/metadata.pdf");
+    const COMMENT_FIXTURE: &[u8] = include_bytes!("fixtures/comment.pdf");
+
+    /// Changes the object and writes the document anew.
+    fn edit(data: &[u8], id: ObjectId, edit: impl FnOnce(&mut Dictionary)) -> Vec<u8> {
+        let mut document = Document::load_mem(data).unwrap();
+        let dictionary = match document.get_object_mut(id).unwrap() {
+            Object::Dictionary(dictionary) => dictionary,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => panic!("Object {id:?} has no dictionary"),
+        };
+        edit(dictionary);
+        let mut out = vec![];
+        document.save_to(&mut out).unwrap();
+
+        out
+    }
+
+    #[test]
+    fn drops_metadata() {
+        let stripped = strip(FIXTURE).unwrap();
+        let document = Document::load_mem(&stripped).unwrap();
+
+        assert!(!document.trailer.has(b"Info"));
+        assert!(!document.trailer.has(b"ID"));
+        for object in document.objects.values() {
+            let dictionary = match object {
+                Object::Dictionary(dictionary) => dictionary,
+                Object::Stream(stream) => &stream.dict,
+                _ => continue,
+            };
+            for key in METADATA_KEYS {
+                assert!(!dictionary.has(key));
+            }
+        }
+        assert!(!contains(&stripped, b"Ivan Petrov"));
+    }
+
+    #[test]
+    fn strips_jpeg_images() {
+        let stripped = strip(FIXTURE).unwrap();
+        let document = Document::load_mem(&stripped).unwrap();
+        let image = document
+            .objects
+            .values()
+            .find_map(|object| {
+                object
+                    .as_stream()
+                    .ok()
+                    .filter(|stream| is_jpeg(&stream.dict).unwrap())
+            })
+            .unwrap();
+
+        assert!(image.content.starts_with(&[0xff, 0xd8, 0xff]));
+        assert!(!contains(&image.content, b"Camera-SN-1234"));
+    }
+
+    #[test]
+    fn refuses_jpeg_under_other_filters() {
+        let data = edit(FIXTURE, (5, 0), |image| {
+            image.set(
+                "Filter",
+                vec![Object::from("FlateDecode"), Object::from("DCTDecode")],
+            );
+        });
+
+        assert!(strip(&data).is_err());
+    }
+
+    #[test]
+    fn refuses_comments() {
+        let err = strip(COMMENT_FIXTURE).unwrap_err();
+
+        assert_eq!(err.to_string(), "PDF has comments");
+    }
+
+    #[test]
+    fn keeps_form_fields() {
+        let data = edit(COMMENT_FIXTURE, (8, 0), |annotation| {
+            annotation.set("Subtype", Object::from("Widget"));
+        });
+
+        assert!(strip(&data).is_ok());
+    }
+
+    #[test]
+    fn refuses_encrypted_documents() {
+        let err = strip(include_bytes!("fixtures/encrypted.pdf")).unwrap_err();
+
+        assert_eq!(err.to_string(), "PDF is encrypted");
+    }
+}
@@ -0,0 +1,89 @@
+use anyhow::Context;
+
+use super::read_u32_be;
+
+const SIGNATURE_LENGTH: usize = 8;
+
+/// Chunks which change how the image looks. Everything else, text chunks, EXIF and timestamps
+/// included, is dropped.
+const KEPT_CHUNKS: &[&[u8; 4]] = &[
+    b"IHDR", b"PLTE", b"IDAT", b"IEND", b"tRNS", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB",
+    b"cICP", b"mDCV", b"cLLI", b"bKGD", b"hIST", b"pHYs", b"sPLT", b"acTL", b"fcTL", b"fdAT",
+];
+
+/// Drops ancillary chunks which don't affect the image and anything after the image end.
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = data[..SIGNATURE_LENGTH].to_vec();
+    let mut pos = SIGNATURE_LENGTH;
+
+    loop {
+        let length = read_u32_be(data, pos)? as usize;
+        let chunk_type: &[u8; 4] = data
+            .get(pos + 4..pos + 8)
+            .context("Unexpected end of PNG")?
+            .try_into()?;
+        // Length, type, data and CRC
+        let chunk = data
+            .get(pos..pos + 12 + length)
+            .context("PNG chunk is out of bounds")?;
+        pos += chunk.len();
+
+        if KEPT_CHUNKS.contains(&chunk_type) {
+            out.extend_from_slice(chunk);
+        } else if chunk_type[0].is_ascii_uppercase() {
+            anyhow::bail!(
+                "Unknown critical PNG chunk {}",
+                String::from_utf8_lossy(chunk_type)
+            );
+        }
+
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::contains;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/text.png");
+
+    fn chunk_types(data: &[u8]) -> Vec<&[u8]> {
+        let mut types = vec![];
+        let mut pos = SIGNATURE_LENGTH;
+        while pos < data.len() {
+            types.push(&data[pos + 4..pos + 8]);
+            pos += 12 + read_u32_be(data, pos).unwrap() as usize;
+        }
+
+        types
+    }
+
+    #[test]
+    fn drops_text_and_exif() {
+        let stripped = strip(FIXTURE).unwrap();
+
+        assert_eq!(
+            chunk_types(&stripped),
+            [b"IHDR".as_slice(), b"IDAT", b"IEND"]
+        );
+        assert!(!contains(&stripped, b"Ivan Petrov"));
+        assert!(!contains(&stripped, b"Camera-SN-1234"));
+    }
+
+    #[test]
+    fn refuses_unknown_critical_chunks() {
+        let mut data = FIXTURE.to_vec();
+        let position = data
+            .windows(4)
+            .position(|window| window == b"tEXt")
+            .unwrap();
+        data[position] = b'T';
+
+        assert!(strip(&data).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Context;
+
+/// `RIFF`, file size and `WEBP`.
+const HEADER_LENGTH: usize = 12;
+/// VP8X flags telling that EXIF and XMP chunks are present.
+const EXIF_FLAG: u8 = 0x08;
+const XMP_FLAG: u8 = 0x04;
+
+/// Chunks of the image itself. EXIF, XMP and unknown chunks are dropped.
+const KEPT_CHUNKS: &[&[u8; 4]] = &[
+    b"VP8 ", b"VP8L", b"VP8X", b"ALPH", b"ANIM", b"ANMF", b"ICCP",
+];
+
+pub fn strip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let riff_end = (read_u32_le(data, 4)? as usize)
+        .checked_add(8)
+        .filter(|&end| end <= data.len())
+        .context("WebP size is out of bounds")?;
+
+    let mut chunks = vec![];
+    let mut pos = HEADER_LENGTH;
+    while pos < riff_end {
+        let chunk_type: &[u8; 4] = data
+            .get(pos..pos + 4)
+            .context("Unexpected end of WebP")?
+            .try_into()?;
+        let length = read_u32_le(data, pos + 4)? as usize;
+        // Chunks are padded to an even size
+        let padded_length = length + length % 2;
+        let chunk = data
+            .get(pos..pos + 8 + padded_length)
+            .context("WebP chunk is out of bounds")?;
+        pos += chunk.len();
+
+        if !KEPT_CHUNKS.contains(&chunk_type) {
+            continue;
+        }
+        let mut chunk = chunk.to_vec();
+        if chunk_type == b"VP8X" {
+            *chunk.get_mut(8).context("VP8X chunk is too short")? &= !(EXIF_FLAG | XMP_FLAG);
+        }
+        chunks.extend_from_slice(&chunk);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LENGTH + chunks.len());
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&((4 + chunks.len()) as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&chunks);
+
+    Ok(out)
+}
+
+fn read_u32_le(data: &[u8], pos: usize) -> anyhow::Result<u32> {
+    let bytes = data.get(pos..pos + 4).context("Unexpected end of WebP")?;
+
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::contains;
+
+    const FIXTURE: &[u8] = include_bytes!("fixtures/exif.webp");
+
+    #[test]
+    fn drops_exif_and_xmp() {
+        let stripped = strip(FIXTURE).unwrap();
+
+        assert_eq!(
+            read_u32_le(&stripped, 4).unwrap() as usize + 8,
+            stripped.len()
+        );
+        assert_eq!(&stripped[HEADER_LENGTH..HEADER_LENGTH + 4], b"VP8X");
+        assert_eq!(stripped[HEADER_LENGTH + 8] & (EXIF_FLAG | XMP_FLAG), 0);
+        assert!(contains(&stripped, b"VP8L"));
+        assert!(!contains(&stripped, b"EXIF"));
+        assert!(!contains(&stripped, b"XMP "));
+        assert!(!contains(&stripped, b"Camera-SN-1234"));
+        assert!(!contains(&stripped, b"Ivan Petrov"));
+    }
+
+    #[test]
+    fn refuses_size_past_the_end() {
+        let mut data = FIXTURE.to_vec();
+        data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(strip(&data).is_err());
+    }
+}
@@ -1,10 +1,24 @@
-use std::{collections::HashMap, path::Path, sync::Arc};
+use std::sync::Arc;
 
 use anyhow::Context;
 use tokio::sync::RwLock;
-use tokio_util::sync::CancellationToken;
-
-use crate::{bot::client::Client as TelegramClient, chats::Chats, config::Config};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{
+    api_tokens::ApiTokens,
+    bot::{
+        callback_data::CallbackSigner, chat_links::ChatLinkSigner, client::Client as TelegramClient,
+    },
+    chats::Chats,
+    config::Config,
+    dialogues::Dialogues,
+    outbox::Outbox,
+    posts::Posts,
+    queue::UpdateQueue,
+    replies::PendingReplies,
+    storage::{check_dir_writable, check_file_writable},
+    updates::ProcessedUpdates,
+};
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
@@ -15,16 +29,45 @@ impl AppState {
         let chats = Chats::open(&config.chats_storage)
             .await
             .context("Failed to open chats storage")?;
-        let user_chats = open_user_chats(&config.user_chats_storage)
+        let dialogues = Dialogues::open(&config)
+            .await
+            .context("Failed to open dialogues storage")?;
+        let processed_updates = ProcessedUpdates::open(&config.updates_storage)
+            .await
+            .context("Failed to open updates storage")?;
+        let outbox = Outbox::open(&config.outbox_storage)
             .await
-            .context("Failed to open user chats storage")?;
+            .context("Failed to open outbox storage")?;
+        let posts = Posts::open(&config.posts.storage)
+            .await
+            .context("Failed to open posts storage")?;
+        let update_queue = UpdateQueue::open(&config.queue.storage, config.queue.capacity)
+            .await
+            .context("Failed to open update queue")?;
+        let callback_signer = CallbackSigner::new(&config.auth);
+        let chat_link_signer = ChatLinkSigner::new(&config.auth);
+        let api_tokens = ApiTokens::load(&config.auth).await?;
+        if api_tokens.is_empty() && !config.auth.allow_unauthenticated {
+            anyhow::bail!(
+                "No api tokens are configured. Set `auth.api_token` or `auth.allow_unauthenticated: true`"
+            );
+        }
 
         Ok(Self(Arc::new(AppStateInner {
             config,
             tg_client,
             chats,
-            user_chats,
+            dialogues,
+            api_tokens: RwLock::new(api_tokens),
+            processed_updates,
+            update_queue,
+            outbox,
+            posts,
+            pending_replies: PendingReplies::default(),
+            callback_signer,
+            chat_link_signer,
             cancellation_token: CancellationToken::new(),
+            tasks: TaskTracker::new(),
         })))
     }
 
@@ -44,13 +87,98 @@ impl AppState {
         self.0.chats.save(&self.0.config.chats_storage).await
     }
 
-    pub fn user_chats(&self) -> &RwLock<HashMap<i64, i64>> {
-        &self.0.user_chats
+    pub fn dialogues(&self) -> &Dialogues {
+        &self.0.dialogues
+    }
+
+    pub async fn save_dialogues(&self) -> anyhow::Result<()> {
+        self.0
+            .dialogues
+            .save(&self.0.config.dialogues.storage)
+            .await
+    }
+
+    /// Webhook secret tokens, reloaded when `anon rotate-secret` changes them.
+    pub fn api_tokens(&self) -> &RwLock<ApiTokens> {
+        &self.0.api_tokens
+    }
+
+    pub fn processed_updates(&self) -> &ProcessedUpdates {
+        &self.0.processed_updates
     }
 
-    pub async fn save_user_chats(&self) -> anyhow::Result<()> {
-        let contents = { serde_json::to_vec_pretty(&*self.0.user_chats.read().await) }?;
-        tokio::fs::write(&self.config().user_chats_storage, contents).await?;
+    pub async fn save_processed_updates(&self) -> anyhow::Result<()> {
+        self.0
+            .processed_updates
+            .save(&self.0.config.updates_storage)
+            .await
+    }
+
+    pub fn update_queue(&self) -> &UpdateQueue {
+        &self.0.update_queue
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.0.outbox
+    }
+
+    pub async fn save_outbox(&self) -> anyhow::Result<()> {
+        self.0.outbox.save(&self.0.config.outbox_storage).await
+    }
+
+    pub fn posts(&self) -> &Posts {
+        &self.0.posts
+    }
+
+    pub async fn save_posts(&self) -> anyhow::Result<()> {
+        self.0.posts.save(&self.0.config.posts.storage).await
+    }
+
+    /// Unix timestamp of the oldest posts their authors can still edit or delete.
+    pub fn edit_window_start(&self) -> i64 {
+        let window = i64::try_from(self.config().posts.edit_window_secs).unwrap_or(i64::MAX);
+
+        chrono::Utc::now().timestamp().saturating_sub(window)
+    }
+
+    pub fn pending_replies(&self) -> &PendingReplies {
+        &self.0.pending_replies
+    }
+
+    pub fn callback_signer(&self) -> &CallbackSigner {
+        &self.0.callback_signer
+    }
+
+    pub fn chat_link_signer(&self) -> &ChatLinkSigner {
+        &self.0.chat_link_signer
+    }
+
+    pub async fn flush_storages(&self) -> anyhow::Result<()> {
+        self.save_chats().await?;
+        self.save_dialogues().await?;
+        self.save_processed_updates().await?;
+        self.save_outbox().await?;
+        self.save_posts().await?;
+
+        Ok(())
+    }
+
+    pub async fn check_storage_writable(&self) -> anyhow::Result<()> {
+        for file in [
+            &self.config().chats_storage,
+            &self.config().dialogues.storage,
+            &self.config().updates_storage,
+            &self.config().outbox_storage,
+            &self.config().posts.storage,
+        ] {
+            check_file_writable(file)
+                .await
+                .with_context(|| format!("Storage file {} is not writable", file.display()))?;
+        }
+        let queue_dir = &self.config().queue.storage;
+        check_dir_writable(queue_dir)
+            .await
+            .with_context(|| format!("Queue directory {} is not writable", queue_dir.display()))?;
 
         Ok(())
     }
@@ -58,26 +186,27 @@ impl AppState {
     pub fn cancellation_token(&self) -> &CancellationToken {
         &self.0.cancellation_token
     }
+
+    /// Tasks spawned while handling updates, e.g. delayed deletions. They are awaited on
+    /// shutdown, so they should finish early once the cancellation token is cancelled.
+    pub fn tasks(&self) -> &TaskTracker {
+        &self.0.tasks
+    }
 }
 
 struct AppStateInner {
     config: Config,
     tg_client: TelegramClient,
     chats: Chats,
-    user_chats: RwLock<HashMap<i64, i64>>,
+    dialogues: Dialogues,
+    api_tokens: RwLock<ApiTokens>,
+    processed_updates: ProcessedUpdates,
+    update_queue: UpdateQueue,
+    outbox: Outbox,
+    posts: Posts,
+    pending_replies: PendingReplies,
+    callback_signer: CallbackSigner,
+    chat_link_signer: ChatLinkSigner,
     cancellation_token: CancellationToken,
-}
-
-async fn open_user_chats(file: &Path) -> anyhow::Result<RwLock<HashMap<i64, i64>>> {
-    if !file.exists() {
-        return Ok(RwLock::new(HashMap::new()));
-    }
-
-    let contents = tokio::fs::read(file)
-        .await
-        .context("Failed to open user chats storage file")?;
-
-    let user_chats = serde_json::from_slice(&contents)?;
-
-    Ok(RwLock::new(user_chats))
+    tasks: TaskTracker,
 }
@@ -0,0 +1,29 @@
+use std::path::Path;
+
+/// Checks that `file` can be written without touching its contents. When the file doesn't exist
+/// yet a probe file is created and removed next to it.
+pub async fn check_file_writable(file: &Path) -> anyhow::Result<()> {
+    if file.exists() {
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(file)
+            .await?;
+
+        return Ok(());
+    }
+
+    let probe_file = file.with_extension("probe");
+    tokio::fs::write(&probe_file, b"").await?;
+    tokio::fs::remove_file(&probe_file).await?;
+
+    Ok(())
+}
+
+/// Checks that files can be created in `dir`.
+pub async fn check_dir_writable(dir: &Path) -> anyhow::Result<()> {
+    let probe_file = dir.join(".probe");
+    tokio::fs::write(&probe_file, b"").await?;
+    tokio::fs::remove_file(&probe_file).await?;
+
+    Ok(())
+}
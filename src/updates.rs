@@ -0,0 +1,158 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::Path,
+};
+
+use anyhow::Context;
+use tokio::sync::Mutex;
+
+/// Number of processed update ids remembered to drop telegram's redeliveries.
+const WINDOW_SIZE: usize = 1000;
+
+/// Recently processed update ids. Telegram redelivers an update when the webhook answers slowly
+/// or with an error, so updates seen here are skipped.
+pub struct ProcessedUpdates(Mutex<Window>);
+
+#[derive(Default)]
+struct Window {
+    processed: VecDeque<i64>,
+    in_progress: HashSet<i64>,
+}
+
+impl ProcessedUpdates {
+    pub async fn open(file: &Path) -> anyhow::Result<Self> {
+        let processed: VecDeque<i64> = match file.exists() {
+            true => {
+                let contents = tokio::fs::read(file).await?;
+
+                serde_json::from_slice(&contents)?
+            }
+            false => VecDeque::new(),
+        };
+
+        Ok(Self(Mutex::new(Window {
+            processed,
+            in_progress: HashSet::new(),
+        })))
+    }
+
+    /// Claims the update for processing. Returns `false` when it is already processed or is being
+    /// processed by a concurrent delivery.
+    pub async fn begin(&self, update_id: i64) -> bool {
+        let mut window = self.0.lock().await;
+        if window.processed.contains(&update_id) {
+            return false;
+        }
+
+        window.in_progress.insert(update_id)
+    }
+
+    pub async fn is_processed(&self, update_id: i64) -> bool {
+        self.0.lock().await.processed.contains(&update_id)
+    }
+
+    /// Releases the claim. Failed updates are forgotten so telegram's retry gets processed.
+    pub async fn finish(&self, update_id: i64, processed: bool) {
+        let mut window = self.0.lock().await;
+        window.in_progress.remove(&update_id);
+
+        if processed {
+            window.processed.push_back(update_id);
+            while window.processed.len() > WINDOW_SIZE {
+                window.processed.pop_front();
+            }
+        }
+    }
+
+    pub async fn save(&self, file: &Path) -> anyhow::Result<()> {
+        let contents = { serde_json::to_vec(&self.0.lock().await.processed) }?;
+        tokio::fs::write(file, contents)
+            .await
+            .context("Failed to write processed updates")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn make_updates() -> ProcessedUpdates {
+        ProcessedUpdates::open(Path::new("/nonexistent/updates.json"))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates() {
+        let updates = make_updates().await;
+
+        assert!(updates.begin(1).await);
+        assert!(!updates.begin(1).await, "in progress");
+        updates.finish(1, true).await;
+        assert!(updates.is_processed(1).await);
+        assert!(!updates.begin(1).await, "processed");
+    }
+
+    #[tokio::test]
+    async fn forgets_failed_updates() {
+        let updates = make_updates().await;
+
+        assert!(updates.begin(1).await);
+        updates.finish(1, false).await;
+        assert!(!updates.is_processed(1).await);
+        assert!(updates.begin(1).await);
+    }
+
+    #[tokio::test]
+    async fn remembers_last_window_of_updates() {
+        let updates = make_updates().await;
+        let window_size = WINDOW_SIZE as i64;
+
+        for update_id in 0..=window_size {
+            assert!(updates.begin(update_id).await);
+            updates.finish(update_id, true).await;
+        }
+
+        assert!(!updates.is_processed(0).await);
+        assert!(updates.begin(0).await, "fell out of the window");
+        for update_id in 1..=window_size {
+            assert!(!updates.begin(update_id).await, "{update_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_in_progress_updates_past_the_window() {
+        let updates = make_updates().await;
+        let window_size = WINDOW_SIZE as i64;
+
+        assert!(updates.begin(-1).await);
+        for update_id in 0..window_size * 2 {
+            assert!(updates.begin(update_id).await);
+            updates.finish(update_id, true).await;
+        }
+
+        // A slow update is claimed however many updates were processed meanwhile
+        assert!(!updates.begin(-1).await);
+        updates.finish(-1, true).await;
+        assert!(updates.is_processed(-1).await);
+        assert!(!updates.begin(-1).await);
+    }
+
+    #[tokio::test]
+    async fn saves_only_processed_updates() {
+        let file = std::env::temp_dir().join(format!("anon-updates-{}.json", std::process::id()));
+        let updates = make_updates().await;
+        assert!(updates.begin(1).await);
+        updates.finish(1, true).await;
+        assert!(updates.begin(2).await);
+        updates.save(&file).await.unwrap();
+
+        let reopened = ProcessedUpdates::open(&file).await.unwrap();
+        assert!(reopened.is_processed(1).await);
+        assert!(reopened.begin(2).await);
+
+        std::fs::remove_file(&file).unwrap();
+    }
+}